@@ -0,0 +1,695 @@
+use crate::{
+    inflate::{crc32, gunzip, inflate},
+    storage::{normalise, FileProvider, Metadata, ReadFile, WriteFile},
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TAR_BLOCK: u64 = 512;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4b50;
+/// The end of central directory record is 22 bytes, followed by a comment of up to 65535
+const ZIP_END_SEARCH: u64 = 22 + 65535;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+const ZIP_ENCRYPTED: u16 = 1;
+/// The extra field holding an entry's sizes and offset when they don't fit in 32 bits
+const ZIP64_EXTRA: u16 = 0x0001;
+
+/// Files inside `.tar`, `.tar.gz` and `.zip` archives, served without unpacking them. Every
+/// archive is indexed when it is mounted, so sizes are known without reading any member, and
+/// members that are stored uncompressed are read straight out of the archive at whatever
+/// offset a client needs. A `.tar.gz` can only be read from the start, so it is decompressed
+/// into memory when it is mounted and stays there, whole, for as long as it is mounted. A
+/// deflated zip member is decompressed into memory when it is opened, and held there only
+/// while a transfer has it open, with every transfer reading it at once sharing the one copy.
+/// Neither can decompress to more than [ArchiveFs::max_inflated_size()]. Uploads are always
+/// refused
+#[derive(Debug)]
+pub struct ArchiveFs {
+    /// Keyed by the member's name with its components joined with `/`
+    members: HashMap<String, Member>,
+    max_inflated_size: u64,
+}
+
+/// Where a member's bytes are, and how they are compressed
+#[derive(Debug)]
+struct Member {
+    source: Source,
+    /// Where the member's data starts in `source`
+    offset: u64,
+    /// Length once decompressed
+    size: u64,
+    compression: Compression,
+    modified: Option<SystemTime>,
+    /// A deflated member's data, for as long as any transfer still has it open
+    inflated: Mutex<Option<Weak<[u8]>>>,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    /// An archive that can be read from anywhere
+    File(Arc<File>),
+    /// A decompressed `.tar.gz`
+    Memory(Arc<[u8]>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Stored,
+    Deflated {
+        compressed_size: u64,
+        crc: u32,
+    },
+    /// A zip member we can't read, either encrypted or compressed with a method other than
+    /// deflate
+    Unsupported,
+}
+
+/// Part of a [Source], which is all a stored member is
+#[derive(Debug)]
+struct Region {
+    source: Source,
+    offset: u64,
+    size: u64,
+}
+
+impl Default for ArchiveFs {
+    fn default() -> Self {
+        Self {
+            members: HashMap::new(),
+            max_inflated_size: Self::DEFAULT_MAX_INFLATED_SIZE,
+        }
+    }
+}
+
+impl ArchiveFs {
+    pub const DEFAULT_MAX_INFLATED_SIZE: u64 = 1 << 30;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The most a `.tar.gz` or a deflated zip member can decompress to, anything bigger is
+    /// refused rather than held in memory. Only applies to archives mounted after it is set
+    pub fn max_inflated_size(mut self, max_inflated_size: u64) -> Self {
+        self.max_inflated_size = max_inflated_size;
+        self
+    }
+
+    /// Serve every file in the archive at `path`, which is recognised by its contents rather
+    /// than its name. A file in more than one mounted archive is served from the one mounted
+    /// last
+    pub fn mount(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mut magic = [0; 4];
+        let len = file.metadata()?.len();
+        file.read_exact_at(&mut magic[..len.min(4) as usize], 0)?;
+
+        let members = if magic.starts_with(GZIP_MAGIC) {
+            let limit = usize::try_from(self.max_inflated_size).unwrap_or(usize::MAX);
+            tar(Source::Memory(gunzip(&fs::read(path)?, limit)?.into()))?
+        } else if magic == ZIP_LOCAL_HEADER.to_le_bytes()
+            || magic == ZIP_END_OF_DIRECTORY.to_le_bytes()
+        {
+            zip(Source::File(Arc::new(file)))?
+        } else {
+            tar(Source::File(Arc::new(file)))?
+        };
+
+        self.members.extend(members);
+        Ok(())
+    }
+
+    fn member(&self, filename: &str) -> io::Result<&Member> {
+        self.members
+            .get(&normalise(filename)?)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+impl FileProvider for ArchiveFs {
+    /// A deflated member is decompressed into memory here unless another transfer already
+    /// has it open, its size comes from the archive's index either way
+    fn open_read(&self, filename: &str) -> io::Result<(Box<dyn ReadFile>, u64)> {
+        let member = self.member(filename)?;
+        let file: Box<dyn ReadFile> = match member.compression {
+            Compression::Stored => Box::new(Region {
+                source: member.source.clone(),
+                offset: member.offset,
+                size: member.size,
+            }),
+            Compression::Deflated { .. } if member.size > self.max_inflated_size => {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "Decompresses to more than the limit",
+                ))
+            }
+            Compression::Deflated {
+                compressed_size,
+                crc,
+            } => Box::new(member.inflate(compressed_size, crc)?),
+            Compression::Unsupported => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Compressed in a way we can't read",
+                ))
+            }
+        };
+        Ok((file, member.size))
+    }
+
+    fn open_write(&self, _: &str) -> io::Result<Box<dyn WriteFile>> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Archives are read only",
+        ))
+    }
+
+    fn metadata(&self, filename: &str) -> io::Result<Metadata> {
+        let member = self.member(filename)?;
+        Ok(Metadata {
+            size: member.size,
+            modified: member.modified,
+        })
+    }
+}
+
+impl Member {
+    /// The member's data, shared with any transfer that already has it open. The lock is held
+    /// while inflating so a burst of requests for the same member only inflate it once
+    fn inflate(&self, compressed_size: u64, crc: u32) -> io::Result<Arc<[u8]>> {
+        let mut inflated = self.inflated.lock().unwrap();
+        if let Some(data) = inflated.as_ref().and_then(Weak::upgrade) {
+            return Ok(data);
+        }
+
+        let mut compressed = vec![0; compressed_size as usize];
+        self.source.read_exact_at(&mut compressed, self.offset)?;
+        let mut data = Vec::with_capacity(self.size as usize);
+        // The size in the archive's index can't be trusted, but it is all we will hold
+        inflate(&compressed, &mut data, self.size as usize)?;
+        if data.len() as u64 != self.size || crc32(&data) != crc {
+            return Err(invalid("Corrupt zip member"));
+        }
+
+        let data = Arc::<[u8]>::from(data);
+        *inflated = Some(Arc::downgrade(&data));
+        Ok(data)
+    }
+}
+
+impl Source {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.read_exact_at(buffer, offset),
+            Self::Memory(data) => data.read_exact_at(buffer, offset),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata()?.len()),
+            Self::Memory(data) => Ok(data.len() as u64),
+        }
+    }
+}
+
+impl ReadFile for Region {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        if offset + buffer.len() as u64 > self.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.source.read_exact_at(buffer, self.offset + offset)
+    }
+}
+
+/// Index a tar archive, ustar with the GNU long name and pax extensions. Only regular files
+/// are served, links and directories are left out
+fn tar(source: Source) -> io::Result<HashMap<String, Member>> {
+    let len = source.len()?;
+    // Anything shorter than a header can't be a tar archive, unless it is empty
+    if len != 0 && len < TAR_BLOCK {
+        return Err(invalid("Not a tar archive"));
+    }
+    let mut members = HashMap::new();
+    let mut offset = 0;
+    // Set by a GNU long name or pax header, for the entry that follows it
+    let mut long_name = None;
+    let mut long_size = None;
+
+    while offset + TAR_BLOCK <= len {
+        let mut header = [0; TAR_BLOCK as usize];
+        source.read_exact_at(&mut header, offset)?;
+        // The archive ends with two blocks of zeroes, but some writers only add one
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        if octal(&header[148..156])? != checksum(&header) {
+            return Err(invalid("Not a tar archive"));
+        }
+
+        let size = match long_size.take() {
+            Some(size) => size,
+            None => tar_number(&header[124..136])?,
+        };
+        let data = offset + TAR_BLOCK;
+        // A size that runs past the end of the archive can be big enough to overflow
+        offset = size
+            .div_ceil(TAR_BLOCK)
+            .checked_mul(TAR_BLOCK)
+            .and_then(|padded| data.checked_add(padded))
+            .filter(|end| *end <= len)
+            .ok_or_else(|| invalid("Truncated tar archive"))?;
+
+        match header[156] {
+            b'L' => {
+                let mut name = vec![0; size as usize];
+                source.read_exact_at(&mut name, data)?;
+                long_name = Some(string(&name));
+            }
+            b'x' => {
+                let mut records = vec![0; size as usize];
+                source.read_exact_at(&mut records, data)?;
+                for (key, value) in pax(&records) {
+                    match key {
+                        "path" => long_name = Some(value.to_owned()),
+                        "size" => long_size = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+            b'0' | b'\0' | b'7' => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let name = string(&header[0..100]);
+                    let prefix = string(&header[345..500]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{prefix}/{name}")
+                    } else {
+                        name
+                    }
+                });
+                let modified = tar_number(&header[136..148])
+                    .ok()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+                // A name that would reach outside the archive can never be asked for
+                if let Ok(name) = normalise(&name) {
+                    let member = Member {
+                        source: source.clone(),
+                        offset: data,
+                        size,
+                        compression: Compression::Stored,
+                        modified,
+                        inflated: Mutex::new(None),
+                    };
+                    members.insert(name, member);
+                }
+            }
+            _ => long_name = None,
+        }
+    }
+    Ok(members)
+}
+
+/// The sum of every byte in a tar header, with the checksum field counted as spaces
+fn checksum(header: &[u8]) -> u64 {
+    let spaces = b' ' as u64 * 8;
+    header.iter().map(|byte| *byte as u64).sum::<u64>()
+        - header[148..156]
+            .iter()
+            .map(|byte| *byte as u64)
+            .sum::<u64>()
+        + spaces
+}
+
+/// A tar header number, in octal unless GNU tar had to store it in base 256
+fn tar_number(field: &[u8]) -> io::Result<u64> {
+    match field.first() {
+        Some(byte) if byte & 0x80 != 0 => Ok(field[1..]
+            .iter()
+            .fold((*byte & 0x7f) as u64, |number, byte| {
+                number << 8 | *byte as u64
+            })),
+        _ => octal(field),
+    }
+}
+
+fn octal(field: &[u8]) -> io::Result<u64> {
+    let digits = string(field);
+    let digits = digits.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("Not a tar archive"))
+}
+
+/// pax records are `<length> <key>=<value>\n`
+fn pax(records: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    records
+        .split(|byte| *byte == b'\n')
+        .filter_map(|record| std::str::from_utf8(record).ok())
+        .filter_map(|record| record.split_once(' ')?.1.split_once('='))
+}
+
+/// A string that ends at its first nul, if it has one
+fn string(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Index a zip archive from its central directory. An entry's zip64 extra field is read for
+/// sizes and offsets that don't fit in 32 bits, but a zip64 end of central directory, which
+/// is only needed for more than 65535 entries or a directory past 4 GiB, is not supported.
+/// Every member has to lie within the archive, so nothing we read later can run off the end
+fn zip(source: Source) -> io::Result<HashMap<String, Member>> {
+    let len = source.len()?;
+    let search = len.min(ZIP_END_SEARCH);
+    let mut tail = vec![0; search as usize];
+    source.read_exact_at(&mut tail, len - search)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|at| u32_at(&tail, *at) == ZIP_END_OF_DIRECTORY)
+        .ok_or_else(|| invalid("Not a zip archive"))?;
+    let entries = u16_at(&tail, end + 10);
+    let directory_size = u32_at(&tail, end + 12);
+    let directory_offset = u32_at(&tail, end + 16);
+    if entries == u16::MAX || directory_size == u32::MAX || directory_offset == u32::MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Zip64 archives are not supported",
+        ));
+    }
+
+    if directory_offset as u64 + directory_size as u64 > len {
+        return Err(invalid("Truncated zip archive"));
+    }
+    let mut directory = vec![0; directory_size as usize];
+    source.read_exact_at(&mut directory, directory_offset as u64)?;
+    let mut members = HashMap::new();
+    let mut at = 0;
+
+    for _ in 0..entries {
+        if directory.len() < at + 46 || u32_at(&directory, at) != ZIP_CENTRAL_HEADER {
+            return Err(invalid("Corrupt zip central directory"));
+        }
+        let flags = u16_at(&directory, at + 8);
+        let method = u16_at(&directory, at + 10);
+        let (time, date) = (u16_at(&directory, at + 12), u16_at(&directory, at + 14));
+        let crc = u32_at(&directory, at + 16);
+        let mut compressed_size = u32_at(&directory, at + 20) as u64;
+        let mut size = u32_at(&directory, at + 24) as u64;
+        let name_length = u16_at(&directory, at + 28) as usize;
+        let extra_length = u16_at(&directory, at + 30) as usize;
+        let comment_length = u16_at(&directory, at + 32) as usize;
+        let mut header_offset = u32_at(&directory, at + 42) as u64;
+        let name = directory
+            .get(at + 46..at + 46 + name_length)
+            .map(string)
+            .ok_or_else(|| invalid("Corrupt zip central directory"))?;
+        let extra = directory
+            .get(at + 46 + name_length..at + 46 + name_length + extra_length)
+            .ok_or_else(|| invalid("Corrupt zip central directory"))?;
+        at += 46 + name_length + extra_length + comment_length;
+
+        // Only the fields that overflowed are in the zip64 extra field, in this order
+        if let Some(mut zip64) = extra_field(extra, ZIP64_EXTRA) {
+            for field in [&mut size, &mut compressed_size, &mut header_offset] {
+                if *field == u32::MAX as u64 {
+                    *field = zip64
+                        .split_off(..8)
+                        .map(|value| u64::from_le_bytes(value.try_into().unwrap()))
+                        .ok_or_else(|| invalid("Corrupt zip64 extra field"))?;
+                }
+            }
+        }
+
+        let name = match normalise(&name) {
+            Ok(normalised) if !name.ends_with('/') => normalised,
+            _ => continue,
+        };
+
+        // The data follows the local header, whose extra field can differ from the one in
+        // the central directory
+        let mut local = [0; 30];
+        if header_offset + 30 > len {
+            return Err(invalid("Truncated zip archive"));
+        }
+        source.read_exact_at(&mut local, header_offset)?;
+        if u32_at(&local, 0) != ZIP_LOCAL_HEADER {
+            return Err(invalid("Corrupt zip local header"));
+        }
+        let offset = header_offset + 30 + u16_at(&local, 26) as u64 + u16_at(&local, 28) as u64;
+
+        let compression = match method {
+            _ if flags & ZIP_ENCRYPTED != 0 => Compression::Unsupported,
+            ZIP_STORED => Compression::Stored,
+            ZIP_DEFLATED => Compression::Deflated {
+                compressed_size,
+                crc,
+            },
+            _ => Compression::Unsupported,
+        };
+        let stored_size = match compression {
+            Compression::Stored => size,
+            _ => compressed_size,
+        };
+        if offset.checked_add(stored_size).is_none_or(|end| end > len) {
+            return Err(invalid("Truncated zip archive"));
+        }
+        let member = Member {
+            source: source.clone(),
+            offset,
+            size,
+            compression,
+            modified: dos_time(date, time),
+            inflated: Mutex::new(None),
+        };
+        members.insert(name, member);
+    }
+    Ok(members)
+}
+
+/// A zip timestamp, which is local time with no zone so we take it as UTC
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf) as i64;
+    let day = (date & 0x1f) as i64;
+    if !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let secs =
+        ((time >> 11) as u64 * 60 + ((time >> 5) & 0x3f) as u64) * 60 + (time & 0x1f) as u64 * 2;
+
+    // Howard Hinnant's `days_from_civil`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(UNIX_EPOCH + Duration::from_secs(days as u64 * 86400 + secs))
+}
+
+/// The data of the record with `id` in a zip extra field, which is a list of records each
+/// with a 2 byte id and 2 byte length
+fn extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let length = u16_at(extra, 2) as usize;
+        let data = extra.get(4..4 + length)?;
+        if u16_at(extra, 0) == id {
+            return Some(data);
+        }
+        extra = &extra[4 + length..];
+    }
+    None
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ustar header for a regular file of `size` bytes, with `size` in base 256 so it can be
+    /// anything
+    fn header(name: &str, size: u64) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        let checksum = format!("{:06o}\0 ", checksum(&header));
+        header[148..156].copy_from_slice(checksum.as_bytes());
+        header
+    }
+
+    fn tar_of(data: Vec<u8>) -> io::Result<HashMap<String, Member>> {
+        tar(Source::Memory(data.into()))
+    }
+
+    /// A zip archive with `data` stored as `name`, with `size` as the sizes in the central
+    /// directory and `extra` as its extra field there
+    fn zip_of(name: &str, data: &[u8], size: u32, extra: &[u8]) -> Vec<u8> {
+        let name_length = (name.len() as u16).to_le_bytes();
+        let crc = crc32(data).to_le_bytes();
+        let mut archive = ZIP_LOCAL_HEADER.to_le_bytes().to_vec();
+        archive.extend([20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        archive.extend(crc);
+        archive.extend([(data.len() as u32).to_le_bytes(); 2].concat());
+        archive.extend(name_length);
+        archive.extend([0, 0]);
+        archive.extend(name.as_bytes());
+        archive.extend(data);
+
+        let directory_offset = archive.len() as u32;
+        archive.extend(ZIP_CENTRAL_HEADER.to_le_bytes());
+        archive.extend([20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        archive.extend(crc);
+        archive.extend([size.to_le_bytes(); 2].concat());
+        archive.extend(name_length);
+        archive.extend((extra.len() as u16).to_le_bytes());
+        archive.extend([0; 14]);
+        archive.extend(name.as_bytes());
+        archive.extend(extra);
+        let directory_size = archive.len() as u32 - directory_offset;
+
+        archive.extend(ZIP_END_OF_DIRECTORY.to_le_bytes());
+        archive.extend([0, 0, 0, 0, 1, 0, 1, 0]);
+        archive.extend(directory_size.to_le_bytes());
+        archive.extend(directory_offset.to_le_bytes());
+        archive.extend([0, 0]);
+        archive
+    }
+
+    fn zip_of_memory(data: Vec<u8>) -> io::Result<HashMap<String, Member>> {
+        zip(Source::Memory(data.into()))
+    }
+
+    /// A zip64 extra field with `values` for the sizes and offset that didn't fit
+    fn zip64(values: &[u64]) -> Vec<u8> {
+        let mut extra = ZIP64_EXTRA.to_le_bytes().to_vec();
+        extra.extend((values.len() as u16 * 8).to_le_bytes());
+        for value in values {
+            extra.extend(value.to_le_bytes());
+        }
+        extra
+    }
+
+    /// A member of `size` bytes that inflates from the raw deflate stream `compressed`
+    fn deflated(compressed: &[u8], size: u64) -> Member {
+        Member {
+            source: Source::Memory(compressed.into()),
+            offset: 0,
+            size,
+            compression: Compression::Deflated {
+                compressed_size: compressed.len() as u64,
+                crc: 0,
+            },
+            modified: None,
+            inflated: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn indexes_a_tar_member() {
+        let mut archive = header("boot/pxelinux.0", 600);
+        archive.extend(vec![7; 1024]);
+        archive.extend(vec![0; 1024]);
+
+        let members = tar_of(archive).unwrap();
+        let member = &members["boot/pxelinux.0"];
+        assert_eq!((member.offset, member.size), (TAR_BLOCK, 600));
+    }
+
+    #[test]
+    fn refuses_a_tar_member_past_the_end() {
+        for size in [1025, u64::MAX - 100, u64::MAX] {
+            let mut archive = header("huge", size);
+            archive.extend(vec![0; 1024]);
+
+            let error = tar_of(archive).unwrap_err();
+            assert_eq!(error.to_string(), "Truncated tar archive");
+        }
+    }
+
+    #[test]
+    fn refuses_a_file_shorter_than_a_tar_header() {
+        let error = tar_of(b"localhost\n".to_vec()).unwrap_err();
+        assert_eq!(error.to_string(), "Not a tar archive");
+        assert!(tar_of(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn indexes_a_zip_member() {
+        let members = zip_of_memory(zip_of("boot/pxelinux.0", b"hello", 5, &[])).unwrap();
+        let member = &members["boot/pxelinux.0"];
+        assert_eq!((member.offset, member.size), (30 + 15, 5));
+        assert!(matches!(member.compression, Compression::Stored));
+    }
+
+    #[test]
+    fn refuses_a_zip_member_past_the_end() {
+        for size in [1000, u32::MAX - 1] {
+            let error = zip_of_memory(zip_of("huge", b"hello", size, &[])).unwrap_err();
+            assert_eq!(error.to_string(), "Truncated zip archive");
+        }
+    }
+
+    #[test]
+    fn reads_sizes_from_a_zip64_extra_field() {
+        let archive = zip_of("big", b"hello", u32::MAX, &zip64(&[5, 5]));
+        assert_eq!(zip_of_memory(archive).unwrap()["big"].size, 5);
+
+        let archive = zip_of("big", b"hello", u32::MAX, &zip64(&[1 << 40, 1 << 40]));
+        let error = zip_of_memory(archive).unwrap_err();
+        assert_eq!(error.to_string(), "Truncated zip archive");
+
+        let archive = zip_of("big", b"hello", u32::MAX, &zip64(&[5]));
+        let error = zip_of_memory(archive).unwrap_err();
+        assert_eq!(error.to_string(), "Corrupt zip64 extra field");
+    }
+
+    #[test]
+    fn refuses_to_inflate_past_the_limit() {
+        // A stored deflate block holding "hello"
+        let compressed = [1, 5, 0, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o'];
+        let mut archives = ArchiveFs::new().max_inflated_size(4);
+        archives
+            .members
+            .insert("big".into(), deflated(&compressed, 5));
+        // The index says 4 bytes, but it inflates to more
+        archives
+            .members
+            .insert("lying".into(), deflated(&compressed, 4));
+
+        for filename in ["big", "lying"] {
+            let error = archives.open_read(filename).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    ops::RangeInclusive,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tftp3o::{
+    log::{Level, Logger},
+    machine::Config,
+};
+
+pub const USAGE: &str = "\
+Usage: tftp3o [OPTIONS]                                serve files over TFTP
+       tftp3o get [OPTIONS] <SERVER> <REMOTE> [LOCAL]  download REMOTE to LOCAL
+       tftp3o put [OPTIONS] <SERVER> <LOCAL> [REMOTE]  upload LOCAL as REMOTE
+
+Server options:
+    -l, --listen <ADDR>              address to listen on, can be repeated [default: 0.0.0.0]
+    -p, --port <PORT>                port to listen on [default: 69]
+    -r, --root <DIR>                 directory to serve [default: .]
+        --read-only                  refuse every upload
+        --in-memory                  copy the root into memory at startup and serve from there,
+                                     uploads are only kept in memory
+        --archive <FILE>             serve the files in this .tar, .tar.gz or .zip instead of
+                                     the root, can be repeated and uploads are refused
+        --max-inflated-size <BYTES>  most a .tar.gz or a compressed .zip member can
+                                     decompress to [default: 1073741824]
+        --transfer-ports <FIRST-LAST>
+                                     only give transfers a port from this range
+        --max-blksize <BYTES>        largest block size clients can negotiate [default: 65464]
+        --max-windowsize <BLOCKS>    largest window clients can negotiate [default: 65535]
+        --max-upload-size <BYTES>    refuse uploads bigger than this
+        --timeout <SECONDS>          retransmit timeout unless the client sets one [default: 1]
+        --retries <COUNT>            retransmits before giving up on a client [default: 5]
+        --idle-timeout <SECONDS>     evict transfers that make no progress for this long [default: 300]
+        --max-sessions <COUNT>       transfers allowed at once [default: 1024]
+        --max-client-sessions <COUNT>
+                                     transfers allowed at once from one address [default: 64]
+        --multicast <ADDR:PORT>      send to clients that ask for multicast through groups
+                                     from this address upwards
+        --metrics <ADDR:PORT>        serve Prometheus metrics over HTTP at /metrics
+        --log-level <LEVEL>          error, warn, info or debug [default: warn]
+        --log-format <FORMAT>        text or json [default: text]
+    -v, --verbose                    log every transfer, the same as --log-level info
+    -q, --quiet                      log nothing
+
+Client options:
+        --blksize <BYTES>            ask for a block size other than 512
+        --windowsize <BLOCKS>        ask for more than one block per ACK
+        --timeout <SECONDS>          retransmit timeout, also negotiated with the server
+        --retries <COUNT>            retransmits before giving up on the server [default: 5]
+
+SERVER is a host with an optional :port, LOCAL and REMOTE default to each other
+
+    -h, --help                       print this help";
+
+const PORT: u16 = 69;
+const BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const BLOCK_SIZE_RANGE: RangeInclusive<usize> = 8..=Config::MAX_BLOCK_SIZE;
+const WINDOW_SIZE_RANGE: RangeInclusive<usize> = 1..=Config::MAX_WINDOW_SIZE;
+const TIMEOUT_RANGE: RangeInclusive<u64> = 1..=255;
+/// Up to a day, anything longer is as good as never
+const IDLE_TIMEOUT_RANGE: RangeInclusive<u64> = 1..=86400;
+/// With the longest timeout that is still seven hours spent on a peer that has gone away
+const RETRIES_RANGE: RangeInclusive<usize> = 0..=100;
+const SESSIONS_RANGE: RangeInclusive<usize> = 1..=usize::MAX;
+
+/// What we have been asked to do
+pub enum Command {
+    Serve(Serve),
+    Get(Transfer),
+    Put(Transfer),
+    Help,
+}
+
+/// Everything needed to run the server
+pub struct Serve {
+    pub listen: Vec<IpAddr>,
+    pub port: u16,
+    pub root: PathBuf,
+    pub in_memory: bool,
+    pub archives: Vec<PathBuf>,
+    pub max_inflated_size: Option<u64>,
+    pub transfer_ports: Option<RangeInclusive<u16>>,
+    pub max_sessions: Option<usize>,
+    pub max_client_sessions: Option<usize>,
+    pub multicast: Option<SocketAddrV4>,
+    pub metrics: Option<SocketAddr>,
+    pub config: Config,
+    pub logger: Logger,
+}
+
+/// A download or upload, `source` is the remote file for a get and the local file for a put
+pub struct Transfer {
+    pub server: String,
+    pub source: String,
+    pub destination: Option<String>,
+    pub block_size: Option<usize>,
+    pub window_size: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub retries: Option<usize>,
+}
+
+/// Work out what to do from the arguments the binary was run with, an [Err] has a message
+/// for the user
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+
+    match args.peek().map(String::as_str) {
+        Some("get") => {
+            args.next();
+            parse_transfer(args).map(|transfer| transfer.map_or(Command::Help, Command::Get))
+        }
+        Some("put") => {
+            args.next();
+            parse_transfer(args).map(|transfer| transfer.map_or(Command::Help, Command::Put))
+        }
+        _ => parse_serve(args).map(|serve| serve.map_or(Command::Help, Command::Serve)),
+    }
+}
+
+/// Returns [None] if we were asked for help
+fn parse_serve(mut args: impl Iterator<Item = String>) -> Result<Option<Serve>, String> {
+    let mut serve = Serve {
+        listen: Vec::new(),
+        port: PORT,
+        root: PathBuf::from("."),
+        in_memory: false,
+        archives: Vec::new(),
+        max_inflated_size: None,
+        transfer_ports: None,
+        max_sessions: None,
+        max_client_sessions: None,
+        multicast: None,
+        metrics: None,
+        config: Config::default(),
+        logger: Logger::default(),
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" | "--listen" => serve.listen.push(parse_value(&arg, args.next())?),
+            "-p" | "--port" => serve.port = value(&arg, args.next(), 1..=u16::MAX)?,
+            "-r" | "--root" => serve.root = parse_value(&arg, args.next())?,
+            "--read-only" => serve.config.read_only = true,
+            "--in-memory" => serve.in_memory = true,
+            "--archive" => serve.archives.push(parse_value(&arg, args.next())?),
+            "--max-inflated-size" => {
+                serve.max_inflated_size = Some(parse_value(&arg, args.next())?)
+            }
+            "--transfer-ports" => serve.transfer_ports = Some(port_range(&arg, args.next())?),
+            "--max-blksize" => {
+                serve.config.max_block_size = value(&arg, args.next(), BLOCK_SIZE_RANGE)?
+            }
+            "--max-windowsize" => {
+                serve.config.max_window_size = value(&arg, args.next(), WINDOW_SIZE_RANGE)?
+            }
+            "--max-upload-size" => {
+                serve.config.max_upload_size = Some(parse_value(&arg, args.next())?)
+            }
+            "--timeout" => {
+                serve.config.timeout = Duration::from_secs(value(&arg, args.next(), TIMEOUT_RANGE)?)
+            }
+            "--retries" => serve.config.retries = value(&arg, args.next(), RETRIES_RANGE)?,
+            "--idle-timeout" => {
+                serve.config.idle_timeout =
+                    Duration::from_secs(value(&arg, args.next(), IDLE_TIMEOUT_RANGE)?)
+            }
+            "--max-sessions" => {
+                serve.max_sessions = Some(value(&arg, args.next(), SESSIONS_RANGE)?)
+            }
+            "--max-client-sessions" => {
+                serve.max_client_sessions = Some(value(&arg, args.next(), SESSIONS_RANGE)?)
+            }
+            "--multicast" => {
+                let first: SocketAddrV4 = parse_value(&arg, args.next())?;
+                if !first.ip().is_multicast() {
+                    return Err(format!("{arg} must be a multicast address"));
+                }
+                serve.multicast = Some(first);
+            }
+            "--metrics" => serve.metrics = Some(parse_value(&arg, args.next())?),
+            "--log-level" => serve.logger.level = Some(parse_value(&arg, args.next())?),
+            "--log-format" => serve.logger.format = parse_value(&arg, args.next())?,
+            "-v" | "--verbose" => serve.logger.level = Some(Level::Info),
+            "-q" | "--quiet" => serve.logger.level = None,
+            "-h" | "--help" => return Ok(None),
+            arg => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+
+    if serve.listen.is_empty() {
+        serve.listen.push(BIND_ADDR);
+    }
+    if serve.in_memory && !serve.archives.is_empty() {
+        return Err("--in-memory can't be used with --archive".into());
+    }
+    // The root isn't served from at all when there are archives
+    if serve.archives.is_empty() && !serve.root.is_dir() {
+        return Err(format!("{} is not a directory", serve.root.display()));
+    }
+
+    Ok(Some(serve))
+}
+
+/// Returns [None] if we were asked for help
+fn parse_transfer(mut args: impl Iterator<Item = String>) -> Result<Option<Transfer>, String> {
+    let mut positional = Vec::new();
+    let mut block_size = None;
+    let mut window_size = None;
+    let mut timeout = None;
+    let mut retries = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--blksize" => block_size = Some(value(&arg, args.next(), BLOCK_SIZE_RANGE)?),
+            "--windowsize" => window_size = Some(value(&arg, args.next(), WINDOW_SIZE_RANGE)?),
+            "--timeout" => {
+                timeout = Some(Duration::from_secs(value(
+                    &arg,
+                    args.next(),
+                    TIMEOUT_RANGE,
+                )?))
+            }
+            "--retries" => retries = Some(value(&arg, args.next(), RETRIES_RANGE)?),
+            "-h" | "--help" => return Ok(None),
+            option if option.starts_with('-') => return Err(format!("Unexpected option {option}")),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let (Some(server), Some(source), destination, None) = (
+        positional.next(),
+        positional.next(),
+        positional.next(),
+        positional.next(),
+    ) else {
+        return Err("Expected a server and one or two file names".into());
+    };
+
+    Ok(Some(Transfer {
+        server,
+        source,
+        destination,
+        block_size,
+        window_size,
+        timeout,
+        retries,
+    }))
+}
+
+fn parse_value<T: FromStr>(option: &str, value: Option<String>) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    let value = value.ok_or_else(|| format!("{option} needs a value"))?;
+    value
+        .parse()
+        .map_err(|error| format!("Invalid value {value:?} for {option}: {error}"))
+}
+
+/// Parse a number and make sure it is in `range`
+fn value<T: FromStr + PartialOrd + Display>(
+    option: &str,
+    value: Option<String>,
+    range: RangeInclusive<T>,
+) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    let value: T = parse_value(option, value)?;
+    if !range.contains(&value) {
+        return Err(format!(
+            "{option} must be between {} and {}",
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(value)
+}
+
+/// A range of ports given as FIRST-LAST
+fn port_range(option: &str, value: Option<String>) -> Result<RangeInclusive<u16>, String> {
+    let value = value.ok_or_else(|| format!("{option} needs a value"))?;
+    let Some((first, last)) = value.split_once('-') else {
+        return Err(format!("{option} must be given as FIRST-LAST"));
+    };
+    let first = self::value(option, Some(first.into()), 1..=u16::MAX)?;
+    let last = self::value(option, Some(last.into()), first..=u16::MAX)?;
+
+    Ok(first..=last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> impl Iterator<Item = String> + '_ {
+        args.split_whitespace().map(String::from)
+    }
+
+    fn serve(line: &str) -> Result<Serve, String> {
+        parse_serve(args(line)).map(|serve| serve.expect("asked for help"))
+    }
+
+    fn transfer(line: &str) -> Result<Transfer, String> {
+        parse_transfer(args(line)).map(|transfer| transfer.expect("asked for help"))
+    }
+
+    fn error<T>(result: Result<T, String>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn parses_server_options() {
+        let serve = serve(
+            "-l 127.0.0.1 --listen ::1 -p 6969 --read-only --transfer-ports 5000-5100 \
+             --max-blksize 1428 --max-windowsize 16 --max-upload-size 1048576 --timeout 3 \
+             --retries 10 --idle-timeout 60 --max-sessions 8 --multicast 239.255.0.1:1758 -v",
+        )
+        .unwrap();
+
+        assert_eq!(
+            serve.listen,
+            [
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert_eq!(serve.port, 6969);
+        assert_eq!(serve.transfer_ports, Some(5000..=5100));
+        assert_eq!(serve.max_sessions, Some(8));
+        assert_eq!(serve.multicast, Some("239.255.0.1:1758".parse().unwrap()));
+        assert_eq!(serve.logger.level, Some(Level::Info));
+        let config = serve.config;
+        assert!(config.read_only);
+        assert_eq!((config.max_block_size, config.max_window_size), (1428, 16));
+        assert_eq!(config.max_upload_size, Some(1_048_576));
+        assert_eq!(config.timeout, Duration::from_secs(3));
+        assert_eq!(config.retries, 10);
+        assert_eq!(config.idle_timeout, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn serves_the_current_directory_on_every_address_by_default() {
+        let serve = serve("").unwrap();
+        assert_eq!(serve.listen, [BIND_ADDR]);
+        assert_eq!((serve.port, serve.root), (PORT, PathBuf::from(".")));
+        assert!(parse_serve(args("--verbose --help")).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_bad_server_options() {
+        for (line, message) in [
+            ("--port", "--port needs a value"),
+            ("--port 0", "--port must be between 1 and 65535"),
+            (
+                "--max-blksize 4",
+                "--max-blksize must be between 8 and 65464",
+            ),
+            ("--retries 101", "--retries must be between 0 and 100"),
+            (
+                "--idle-timeout 0",
+                "--idle-timeout must be between 1 and 86400",
+            ),
+            (
+                "--transfer-ports 5000",
+                "--transfer-ports must be given as FIRST-LAST",
+            ),
+            (
+                "--transfer-ports 5100-5000",
+                "--transfer-ports must be between 5100 and 65535",
+            ),
+            (
+                "--multicast 192.0.2.1:1758",
+                "--multicast must be a multicast address",
+            ),
+            (
+                "--in-memory --archive boot.tar",
+                "--in-memory can't be used with --archive",
+            ),
+            ("--bogus", "Unexpected argument --bogus"),
+        ] {
+            assert_eq!(error(serve(line)), message, "{line}");
+        }
+        assert!(error(serve("--log-level loud")).starts_with("Invalid value \"loud\""));
+    }
+
+    #[test]
+    fn only_needs_a_root_directory_without_archives() {
+        let missing = "/nonexistent/tftp3o/root";
+        assert_eq!(
+            error(serve(&format!("--root {missing}"))),
+            format!("{missing} is not a directory")
+        );
+        let serve = serve(&format!("--root {missing} --archive boot.tar")).unwrap();
+        assert_eq!(serve.archives, [PathBuf::from("boot.tar")]);
+    }
+
+    #[test]
+    fn parses_transfers() {
+        let transfer = transfer(
+            "--blksize 1428 192.0.2.1:6969 --windowsize 8 boot/pxelinux.0 pxelinux.0 \
+             --timeout 2 --retries 3",
+        )
+        .unwrap();
+        assert_eq!(transfer.server, "192.0.2.1:6969");
+        assert_eq!(transfer.source, "boot/pxelinux.0");
+        assert_eq!(transfer.destination.as_deref(), Some("pxelinux.0"));
+        assert_eq!(
+            (transfer.block_size, transfer.window_size),
+            (Some(1428), Some(8))
+        );
+        assert_eq!(transfer.timeout, Some(Duration::from_secs(2)));
+        assert_eq!(transfer.retries, Some(3));
+
+        let transfer = self::transfer("192.0.2.1 motd").unwrap();
+        assert_eq!(transfer.destination, None);
+        assert_eq!((transfer.block_size, transfer.retries), (None, None));
+        assert!(parse_transfer(args("192.0.2.1 -h")).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_bad_transfers() {
+        for (line, message) in [
+            ("192.0.2.1", "Expected a server and one or two file names"),
+            (
+                "192.0.2.1 a b c",
+                "Expected a server and one or two file names",
+            ),
+            (
+                "--windowsize 0 192.0.2.1 a",
+                "--windowsize must be between 1 and 65535",
+            ),
+            (
+                "--timeout 256 192.0.2.1 a",
+                "--timeout must be between 1 and 255",
+            ),
+            ("--blksize", "--blksize needs a value"),
+            ("-x 192.0.2.1 a", "Unexpected option -x"),
+        ] {
+            assert_eq!(error(transfer(line)), message, "{line}");
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{
+    error::{Error, ProtocolError},
+    tftp::{Acknowledgement, Data, Request, Serialise, Tftp, TftpOption, UDP_BUFFER_SIZE},
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+/// How far a transfer has got, given to the progress callback every time the server is
+/// acknowledged or acknowledges us
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Bytes transferred so far
+    pub bytes: u64,
+    /// Size of the whole file, if it was given with the tsize option
+    pub total: Option<u64>,
+}
+
+/// Downloads files from and uploads files to a single TFTP server, asking it for whichever
+/// options have been set
+#[derive(Debug)]
+pub struct Client {
+    socket: UdpSocket,
+    server: SocketAddr,
+    block_size: Option<usize>,
+    window_size: Option<usize>,
+    /// How long we wait to hear from the server, also negotiated if `negotiate_timeout`
+    timeout: Duration,
+    negotiate_timeout: bool,
+    retries: usize,
+}
+
+/// A single download or upload, from the request until the final ACK
+struct Transfer<'client> {
+    client: &'client Client,
+    /// The port the server is sending from, set by its first reply
+    tid: Option<SocketAddr>,
+    block_size: usize,
+    window_size: usize,
+    total: Option<u64>,
+    /// Everything we sent last, in case it needs to be sent again
+    last_sent: Vec<Vec<u8>>,
+}
+
+impl Client {
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+    const DEFAULT_RETRIES: usize = 5;
+    const MODE: &str = "octet";
+
+    /// Bind a local socket for talking to `server`, which is where requests are sent
+    pub fn connect(server: impl ToSocketAddrs) -> io::Result<Self> {
+        let server = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No server address"))?;
+        let socket = match server {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+        };
+
+        Ok(Self {
+            socket,
+            server,
+            block_size: None,
+            window_size: None,
+            timeout: Self::DEFAULT_TIMEOUT,
+            negotiate_timeout: false,
+            retries: Self::DEFAULT_RETRIES,
+        })
+    }
+
+    /// Ask for a block size other than the RFC 1350 512 bytes
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Ask for a window of more than one block per ACK
+    pub fn window_size(mut self, window_size: usize) -> Self {
+        self.window_size = Some(window_size);
+        self
+    }
+
+    /// Wait this long before retransmitting, and ask the server to do the same. The timeout
+    /// option only has whole seconds so the server may round it
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.negotiate_timeout = true;
+        self
+    }
+
+    /// How many times we retransmit before giving up on the server
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Download `filename` into `file`, returning the number of bytes received
+    pub fn get(
+        &self,
+        filename: &str,
+        file: impl Write,
+        progress: impl FnMut(Progress),
+    ) -> Result<u64, Error> {
+        let mut transfer = Transfer::new(self);
+        let result = transfer.get(filename, file, progress);
+        if let Err(error) = &result {
+            transfer.fail(error);
+        }
+        result
+    }
+
+    /// Upload everything in `file` as `filename`, returning the number of bytes sent. If we
+    /// know the `size` of the file up front the server is told with the tsize option
+    pub fn put(
+        &self,
+        filename: &str,
+        file: impl Read,
+        size: Option<u64>,
+        progress: impl FnMut(Progress),
+    ) -> Result<u64, Error> {
+        let mut transfer = Transfer::new(self);
+        let result = transfer.put(filename, file, size, progress);
+        if let Err(error) = &result {
+            transfer.fail(error);
+        }
+        result
+    }
+
+    /// A request for `filename` with every option we have been asked to negotiate, and the
+    /// tsize option if we have a `transfer_size` to send
+    fn request<'request>(
+        &self,
+        filename: &'request str,
+        transfer_size: Option<u64>,
+    ) -> Request<'request> {
+        let mut options = Vec::new();
+        if let Some(transfer_size) = transfer_size {
+            options.push(TftpOption::TransferSize(transfer_size as usize));
+        }
+        if let Some(block_size) = self.block_size {
+            options.push(TftpOption::BlockSize(block_size));
+        }
+        if let Some(window_size) = self.window_size {
+            options.push(TftpOption::WindowSize(window_size));
+        }
+        if self.negotiate_timeout {
+            options.push(TftpOption::Timeout(
+                self.timeout.as_secs().clamp(1, 255) as usize
+            ));
+        }
+
+        Request {
+            filename,
+            mode: Self::MODE,
+            options,
+        }
+    }
+}
+
+impl<'client> Transfer<'client> {
+    const DEFAULT_BLOCK_SIZE: usize = 512;
+    const DEFAULT_WINDOW_SIZE: usize = 1;
+
+    fn new(client: &'client Client) -> Self {
+        Self {
+            client,
+            tid: None,
+            block_size: Transfer::DEFAULT_BLOCK_SIZE,
+            window_size: Transfer::DEFAULT_WINDOW_SIZE,
+            total: None,
+            last_sent: Vec::new(),
+        }
+    }
+
+    /// Acknowledge each window as it arrives. Anything out of order gets a single ACK for the
+    /// last block we have so the server can resend from there, and the next window counts
+    /// from that ACK
+    fn get(
+        &mut self,
+        filename: &str,
+        mut file: impl Write,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<u64, Error> {
+        // Asking for tsize with 0 gets us the size of the file to report progress against
+        let request = Tftp::ReadRequest(self.client.request(filename, Some(0))).serialise();
+        self.send(vec![request])?;
+
+        let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+        let mut block: u64 = 0;
+        // The last block we sent an ACK for
+        let mut acknowledged: u64 = 0;
+        let mut bytes: u64 = 0;
+        let mut negotiated = false;
+        let mut nacked = false;
+
+        loop {
+            match self.receive(&mut buffer)? {
+                // The server sends the OACK again if our ACK of it went missing
+                Tftp::OptionAcknowledgement(oack) if block == 0 => {
+                    if !negotiated {
+                        self.negotiate(&oack.options);
+                        negotiated = true;
+                    }
+                    self.acknowledge(block)?;
+                }
+                Tftp::Data(data) if data.block == (block + 1) as u16 => {
+                    // A server that ignores our options goes straight to the first block
+                    negotiated = true;
+                    nacked = false;
+                    if data.data.len() > self.block_size {
+                        return Err(ProtocolError::BlockTooLarge {
+                            block_size: self.block_size,
+                            actual: data.data.len(),
+                        }
+                        .into());
+                    }
+
+                    file.write_all(data.data)?;
+                    block += 1;
+                    bytes += data.data.len() as u64;
+
+                    let last = data.data.len() < self.block_size;
+                    if last || block - acknowledged == self.window_size as u64 {
+                        self.acknowledge(block)?;
+                        acknowledged = block;
+                        progress(Progress {
+                            bytes,
+                            total: self.total,
+                        });
+                    }
+                    if last {
+                        file.flush()?;
+                        return Ok(bytes);
+                    }
+                }
+                Tftp::Data(_) => {
+                    if !nacked {
+                        nacked = true;
+                        self.acknowledge(block)?;
+                        acknowledged = block;
+                    }
+                }
+                packet => return Err(ProtocolError::UnexpectedPacket(packet.name()).into()),
+            }
+        }
+    }
+
+    /// Keep a window of blocks in flight, sending more as the server acknowledges them. The
+    /// first repeat of an ACK means the server lost something and we resend from there
+    fn put(
+        &mut self,
+        filename: &str,
+        mut file: impl Read,
+        size: Option<u64>,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<u64, Error> {
+        let request = Tftp::WriteRequest(self.client.request(filename, size)).serialise();
+        self.total = size;
+        self.send(vec![request])?;
+
+        let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+        match self.receive(&mut buffer)? {
+            Tftp::OptionAcknowledgement(oack) => self.negotiate(&oack.options),
+            Tftp::Acknowledgement(ack) if ack.block == 0 => {}
+            packet => return Err(ProtocolError::UnexpectedPacket(packet.name()).into()),
+        }
+
+        // Blocks sent but not yet acknowledged, starting at `block + 1`
+        let mut window: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut block: u64 = 0;
+        let mut bytes: u64 = 0;
+        let mut read_all = false;
+        let mut resent = None;
+
+        loop {
+            let sent = window.len();
+            while !read_all && window.len() < self.window_size {
+                let data = read_block(&mut file, self.block_size)?;
+                read_all = data.len() < self.block_size;
+                window.push_back(data);
+            }
+            let packets: Vec<Vec<u8>> = window
+                .iter()
+                .enumerate()
+                .map(|(index, data)| {
+                    let data = Data {
+                        block: (block + 1 + index as u64) as u16,
+                        data,
+                    };
+                    Tftp::Data(data).serialise()
+                })
+                .collect();
+            if window.len() > sent {
+                self.send_new(packets, sent)?;
+            }
+
+            let acked = loop {
+                match self.receive(&mut buffer)? {
+                    Tftp::Acknowledgement(ack) => {
+                        let acked = (block..=block + window.len() as u64)
+                            .find(|acked| *acked as u16 == ack.block);
+                        match acked {
+                            Some(acked) if acked > block => break acked,
+                            // The server is missing the block after this one
+                            Some(_) if resent != Some(block) => {
+                                resent = Some(block);
+                                self.resend()?;
+                            }
+                            _ => {}
+                        }
+                    }
+                    // Our first block went missing so the server has sent its OACK again
+                    Tftp::OptionAcknowledgement(_) if block == 0 => {}
+                    packet => return Err(ProtocolError::UnexpectedPacket(packet.name()).into()),
+                }
+            };
+
+            for _ in block..acked {
+                if let Some(data) = window.pop_front() {
+                    bytes += data.len() as u64;
+                }
+            }
+            block = acked;
+            progress(Progress {
+                bytes,
+                total: self.total,
+            });
+
+            if read_all && window.is_empty() {
+                return Ok(bytes);
+            }
+        }
+    }
+
+    /// Take on whatever the server accepted, anything it left out stays at the default
+    fn negotiate(&mut self, options: &[TftpOption]) {
+        for option in options {
+            match option {
+                TftpOption::BlockSize(block_size) => self.block_size = *block_size,
+                TftpOption::WindowSize(window_size) => self.window_size = *window_size,
+                TftpOption::TransferSize(size) => self.total = Some(*size as u64),
+                _ => {}
+            }
+        }
+    }
+
+    fn acknowledge(&mut self, block: u64) -> io::Result<()> {
+        let ack = Acknowledgement {
+            block: block as u16,
+        };
+        self.send(vec![Tftp::Acknowledgement(ack).serialise()])
+    }
+
+    /// Send `packets` to the server, keeping them in case they need to be sent again
+    fn send(&mut self, packets: Vec<Vec<u8>>) -> io::Result<()> {
+        self.last_sent = packets;
+        self.resend()
+    }
+
+    /// Like [Transfer::send()] but only the packets from `first` on go out now, the rest have
+    /// already been sent and are only kept for retransmitting
+    fn send_new(&mut self, packets: Vec<Vec<u8>>, first: usize) -> io::Result<()> {
+        let peer = self.tid.unwrap_or(self.client.server);
+        for packet in &packets[first..] {
+            self.client.socket.send_to(packet, peer)?;
+        }
+        self.last_sent = packets;
+        Ok(())
+    }
+
+    fn resend(&self) -> io::Result<()> {
+        let peer = self.tid.unwrap_or(self.client.server);
+        for packet in &self.last_sent {
+            self.client.socket.send_to(packet, peer)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next packet from the server, retransmitting whenever it goes quiet. The
+    /// first reply to our request sets the server's TID, and anyone else is told they have the
+    /// wrong one. An ERROR packet from the server ends the transfer
+    fn receive<'buffer>(&mut self, buffer: &'buffer mut [u8]) -> Result<Tftp<'buffer>, Error> {
+        self.client
+            .socket
+            .set_read_timeout(Some(self.client.timeout))?;
+        let mut retries = 0;
+
+        let len = loop {
+            match self.client.socket.recv_from(buffer) {
+                Ok((len, peer)) if self.tid.is_none_or(|tid| tid == peer) => {
+                    if peer.ip() != self.client.server.ip() {
+                        continue;
+                    }
+                    self.tid = Some(peer);
+                    break len;
+                }
+                Ok((_, peer)) => {
+                    let error = ProtocolError::UnknownTransferId.into();
+                    _ = self
+                        .client
+                        .socket
+                        .send_to(&Tftp::serialise_error(&error), peer);
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if retries >= self.client.retries {
+                        return Err(ProtocolError::TimedOut { retries }.into());
+                    }
+                    retries += 1;
+                    self.resend()?;
+                }
+                Err(error) => return Err(error.into()),
+            }
+        };
+
+        match Tftp::parse(&buffer[..len])? {
+            Tftp::Error(error) => Err(ProtocolError::Remote {
+                code: error.code,
+                message: error.message,
+            }
+            .into()),
+            packet => Ok(packet),
+        }
+    }
+
+    /// Let the server know we have given up, unless it was the one that gave up on us
+    fn fail(&self, error: &Error) {
+        if let (Some(tid), false) = (
+            self.tid,
+            matches!(error, Error::Protocol(ProtocolError::Remote { .. })),
+        ) {
+            _ = self
+                .client
+                .socket
+                .send_to(&Tftp::serialise_error(error), tid);
+        }
+    }
+}
+
+/// Read a whole block from `file`, only the final block of a file comes back short
+fn read_block(file: &mut impl Read, block_size: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(block_size);
+    file.take(block_size as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
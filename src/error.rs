@@ -1,4 +1,125 @@
+use crate::tftp::ErrorCode;
+use std::{fmt, io, time::Duration};
+
 #[derive(Debug)]
 pub enum Error {
-    InvalidTftpOpCode(u8),
+    /// The datagram we received is not a valid TFTP packet
+    Parse(ParseError),
+    /// A valid packet that does not make sense for the current state of the transfer
+    Protocol(ProtocolError),
+    /// Something went wrong reading, writing or sending a file
+    Io(io::Error),
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidOpCode(u16),
+    /// The packet ended before we had read the number of bytes we needed
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    /// A filename, mode or option was missing its NULL terminator
+    UnterminatedString,
+    InvalidUtf8,
+    /// A transfer mode other than octet or netascii
+    UnsupportedMode(String),
+    InvalidOptionValue {
+        option: String,
+        value: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// We received an ACK or DATA from a client that has no transfer in progress
+    UnknownTransferId,
+    /// A packet that is valid TFTP but not one the server should ever receive here
+    UnexpectedPacket(&'static str),
+    /// The client sent a DATA packet larger than the negotiated block size
+    BlockTooLarge { block_size: usize, actual: usize },
+    /// The client stopped replying and we ran out of retransmits
+    TimedOut { retries: usize },
+    /// The transfer went too long without moving on and was evicted
+    Idle(Duration),
+    /// The server, or the client's address, already has as many transfers as it is allowed
+    TooManyTransfers,
+    /// An option whose value we can't agree to, even by offering the client something else
+    OptionOutOfRange { option: &'static str, value: usize },
+    /// A write request to a server that only serves files
+    ReadOnly,
+    /// The other end sent us an ERROR packet and gave up on the transfer
+    Remote { code: ErrorCode, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(error) => write!(f, "{error}"),
+            Self::Protocol(error) => write!(f, "{error}"),
+            Self::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpCode(op_code) => write!(f, "Invalid opcode {op_code}"),
+            Self::Truncated { expected, actual } => {
+                write!(
+                    f,
+                    "Packet truncated, expected {expected} bytes got {actual}"
+                )
+            }
+            Self::UnterminatedString => write!(f, "Unterminated string"),
+            Self::InvalidUtf8 => write!(f, "String is not valid UTF-8"),
+            Self::UnsupportedMode(mode) => write!(f, "Unsupported mode {mode}"),
+            Self::InvalidOptionValue { option, value } => {
+                write!(f, "Invalid value {value:?} for option {option}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownTransferId => write!(f, "Unknown transfer ID"),
+            Self::UnexpectedPacket(packet) => write!(f, "Unexpected {packet} packet"),
+            Self::BlockTooLarge { block_size, actual } => {
+                write!(f, "Block of {actual} bytes exceeds block size {block_size}")
+            }
+            Self::TimedOut { retries } => write!(f, "Timed out after {retries} retries"),
+            Self::Idle(timeout) => {
+                write!(f, "No progress for {} seconds", timeout.as_secs())
+            }
+            Self::TooManyTransfers => write!(f, "Too many transfers in progress"),
+            Self::OptionOutOfRange { option, value } => {
+                write!(f, "Value {value} for option {option} is out of range")
+            }
+            Self::ReadOnly => write!(f, "Server is read only"),
+            Self::Remote { code, message } => write!(f, "Error {}: {message}", *code as u16),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(error: ProtocolError) -> Self {
+        Self::Protocol(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
 }
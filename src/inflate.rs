@@ -0,0 +1,550 @@
+use std::io;
+
+/// The order code length code lengths are sent in by a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const MAX_BITS: usize = 15;
+const END_OF_BLOCK: u16 = 256;
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 8];
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+/// The CRC-32 gzip and zip check their contents with
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Decompress a whole gzip file (RFC 1952), which can be several members one after another,
+/// refusing it if it comes to more than `limit` bytes
+pub(crate) fn gunzip(mut input: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+
+    while !input.is_empty() {
+        let header = take(&mut input, 4)?;
+        if header[..3] != GZIP_MAGIC {
+            return Err(invalid("Not a gzip file"));
+        }
+        let flags = header[3];
+        // Modification time, extra flags and OS
+        take(&mut input, 6)?;
+        if flags & FEXTRA != 0 {
+            let length = take(&mut input, 2)?;
+            take(
+                &mut input,
+                u16::from_le_bytes([length[0], length[1]]) as usize,
+            )?;
+        }
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let end = input.iter().position(|byte| *byte == 0);
+                take(
+                    &mut input,
+                    end.ok_or_else(|| invalid("Truncated gzip header"))? + 1,
+                )?;
+            }
+        }
+        if flags & FHCRC != 0 {
+            take(&mut input, 2)?;
+        }
+
+        let start = output.len();
+        let consumed = inflate(input, &mut output, limit)?;
+        input = &input[consumed..];
+
+        let trailer = take(&mut input, 8)?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if crc != crc32(&output[start..]) || size != (output.len() - start) as u32 {
+            return Err(invalid("Corrupt gzip file"));
+        }
+    }
+    Ok(output)
+}
+
+/// Decompress a raw deflate stream (RFC 1951) onto the end of `output`, returning how many
+/// bytes of `input` it took up. A few bytes can inflate to gigabytes, so it stops with an
+/// error rather than let `output` grow past `limit`
+pub(crate) fn inflate(input: &[u8], output: &mut Vec<u8>, limit: usize) -> io::Result<usize> {
+    let mut bits = Bits {
+        input,
+        position: 0,
+        buffer: 0,
+        count: 0,
+    };
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, output, limit)?,
+            1 => {
+                let (lengths, distances) = fixed();
+                codes(&mut bits, output, limit, &lengths, &distances)?;
+            }
+            2 => {
+                let (lengths, distances) = dynamic(&mut bits)?;
+                codes(&mut bits, output, limit, &lengths, &distances)?;
+            }
+            _ => return Err(invalid("Invalid deflate block type")),
+        }
+        if last {
+            return Ok(bits.position);
+        }
+    }
+}
+
+/// Reads a deflate stream a few bits at a time, least significant bit first
+struct Bits<'input> {
+    input: &'input [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, count: u32) -> io::Result<u32> {
+        while self.count < count {
+            let byte = *self.input.get(self.position).ok_or_else(truncated)?;
+            self.buffer |= (byte as u32) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Throw away what is left of the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, as how many codes there are of each length and the symbols in
+/// the order of their codes
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build a code from the length of each symbol's code, where 0 means the symbol is unused.
+    /// A code that is incomplete is allowed, decoding one of its missing codes is an error
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err(invalid("Over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+
+        counts[0] = 0;
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = *count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("Invalid Huffman code"))
+    }
+}
+
+/// A block sent as it is
+fn stored(bits: &mut Bits, output: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    bits.align();
+    let header = bits
+        .input
+        .get(bits.position..bits.position + 4)
+        .ok_or_else(truncated)?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    let complement = u16::from_le_bytes([header[2], header[3]]);
+    if length != !complement {
+        return Err(invalid("Corrupt stored deflate block"));
+    }
+    bits.position += 4;
+
+    let data = bits
+        .input
+        .get(bits.position..bits.position + length as usize)
+        .ok_or_else(truncated)?;
+    if output.len() + data.len() > limit {
+        return Err(too_big());
+    }
+    output.extend_from_slice(data);
+    bits.position += length as usize;
+    Ok(())
+}
+
+/// The codes RFC 1951 fixes for blocks that don't send their own
+fn fixed() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    // Both are complete codes, so they can't fail
+    let lengths = Huffman::new(&lengths).unwrap();
+    let distances = Huffman::new(&[5; 30]).unwrap();
+    (lengths, distances)
+}
+
+/// Read the codes a block sends ahead of its data
+fn dynamic(bits: &mut Bits) -> io::Result<(Huffman, Huffman)> {
+    let length_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_length_count = bits.take(4)? as usize + 4;
+    if length_count > 286 || distance_count > 30 {
+        return Err(invalid("Too many deflate codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[*index] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(length_count + distance_count);
+    while lengths.len() < length_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| invalid("Repeat with no previous length"))?;
+                (previous, 3 + bits.take(2)?)
+            }
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        if lengths.len() + repeat as usize > length_count + distance_count {
+            return Err(invalid("Too many deflate code lengths"));
+        }
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(invalid("No end of block code"));
+    }
+
+    let distances = Huffman::new(&lengths[length_count..])?;
+    let lengths = Huffman::new(&lengths[..length_count])?;
+    Ok((lengths, distances))
+}
+
+/// Decode literals and back references until the end of the block
+fn codes(
+    bits: &mut Bits,
+    output: &mut Vec<u8>,
+    limit: usize,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = lengths.decode(bits)?;
+        if symbol < END_OF_BLOCK {
+            if output.len() == limit {
+                return Err(too_big());
+            }
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let symbol = (symbol - 257) as usize;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(invalid("Invalid deflate length"));
+        }
+        let length =
+            LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+        let symbol = distances.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(invalid("Invalid deflate distance"));
+        }
+        let distance =
+            DISTANCE_BASE[symbol] as usize + bits.take(DISTANCE_EXTRA[symbol] as u32)? as usize;
+        if distance > output.len() {
+            return Err(invalid("Deflate distance is too far back"));
+        }
+        if output.len() + length > limit {
+            return Err(too_big());
+        }
+
+        // The copy can overlap what it is copying, so it has to go a byte at a time
+        let start = output.len() - distance;
+        for index in start..start + length {
+            output.push(output[index]);
+        }
+    }
+}
+
+fn take<'input>(input: &mut &'input [u8], length: usize) -> io::Result<&'input [u8]> {
+    if input.len() < length {
+        return Err(truncated());
+    }
+    let (taken, rest) = input.split_at(length);
+    *input = rest;
+    Ok(taken)
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Compressed data is truncated")
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        "Decompresses to more than the limit",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "hello hello hello" as zlib compresses it, in a single fixed Huffman block
+    const FIXED: &str = "cb48cdc9c957c8409000";
+    /// [random()] of 128 bytes as zlib compresses it, in a single dynamic Huffman block
+    const DYNAMIC: &str = "558c81090040080267bdf61fe2e52ae84b50cc84ca50845187d052e87f5ea3a36aaf8e4535d179bcbbb40dc103";
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// `a`s and `b`s picked by a linear congruential generator, so there are back references
+    /// for zlib to find but too few to make a fixed block worth it
+    fn random(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345) & 0x7fff_ffff;
+                b'a' + (state >> 16) as u8 % 2
+            })
+            .collect()
+    }
+
+    fn stored_block(data: &[u8], last: bool) -> Vec<u8> {
+        let length = data.len() as u16;
+        let mut block = vec![last as u8];
+        block.extend_from_slice(&length.to_le_bytes());
+        block.extend_from_slice(&(!length).to_le_bytes());
+        block.extend_from_slice(data);
+        block
+    }
+
+    /// A gzip member holding `data` in a stored block, with a file name if there is one
+    fn gzip(data: &[u8], name: Option<&str>) -> Vec<u8> {
+        let flags = if name.is_some() { FNAME } else { 0 };
+        let mut member = vec![0x1f, 0x8b, 8, flags, 0, 0, 0, 0, 0, 255];
+        if let Some(name) = name {
+            member.extend_from_slice(name.as_bytes());
+            member.push(0);
+        }
+        member.extend(stored_block(data, true));
+        member.extend_from_slice(&crc32(data).to_le_bytes());
+        member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        member
+    }
+
+    fn inflated(input: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+        let mut output = Vec::new();
+        let consumed = inflate(input, &mut output, usize::MAX)?;
+        Ok((output, consumed))
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn inflates_stored_blocks() {
+        let mut input = stored_block(b"hello ", false);
+        input.extend(stored_block(b"world", true));
+        let length = input.len();
+        input.extend_from_slice(b"trailing");
+
+        assert_eq!(inflated(&input).unwrap(), (b"hello world".to_vec(), length));
+    }
+
+    #[test]
+    fn inflates_a_fixed_block() {
+        let mut input = hex(FIXED);
+        input.extend_from_slice(b"trailing");
+
+        assert_eq!(
+            inflated(&input).unwrap(),
+            (b"hello hello hello".to_vec(), 10)
+        );
+    }
+
+    #[test]
+    fn inflates_a_dynamic_block() {
+        let input = hex(DYNAMIC);
+        assert_eq!(inflated(&input).unwrap(), (random(128), input.len()));
+    }
+
+    #[test]
+    fn gunzips_every_member() {
+        let mut input = gzip(b"first member, ", Some("first.txt"));
+        input.extend(gzip(b"second member", None));
+
+        assert_eq!(
+            gunzip(&input, usize::MAX).unwrap(),
+            b"first member, second member"
+        );
+    }
+
+    #[test]
+    fn refuses_a_corrupt_stored_block() {
+        let mut input = stored_block(b"hello", true);
+        input[3] ^= 1;
+
+        let error = inflated(&input).unwrap_err();
+        assert_eq!(error.to_string(), "Corrupt stored deflate block");
+    }
+
+    #[test]
+    fn refuses_a_gzip_member_with_the_wrong_crc() {
+        let mut input = gzip(b"hello", None);
+        let crc = input.len() - 8;
+        input[crc] ^= 1;
+
+        let error = gunzip(&input, usize::MAX).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Corrupt gzip file");
+    }
+
+    #[test]
+    fn refuses_truncated_input() {
+        let fixed = hex(FIXED);
+        let dynamic = hex(DYNAMIC);
+        let stored = stored_block(b"hello", true);
+        let gzip = gzip(b"hello", None);
+
+        for input in [
+            &fixed[..fixed.len() - 2],
+            &dynamic[..dynamic.len() / 2],
+            &stored[..stored.len() - 1],
+            &[][..],
+        ] {
+            assert_eq!(
+                inflated(input).unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+        assert_eq!(
+            gunzip(&gzip[..gzip.len() - 1], usize::MAX)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn refuses_a_distance_before_the_start() {
+        // A fixed block with the literal `a` and then a copy of 3 bytes from 2 back
+        let error = inflated(&hex("4b044200")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Deflate distance is too far back");
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let fixed = hex(FIXED);
+        let mut output = Vec::new();
+        assert_eq!(inflate(&fixed, &mut output, 17).unwrap(), 10);
+
+        let stored = stored_block(b"hello", true);
+        for (input, limit) in [(&fixed[..], 16), (&fixed[..], 3), (&stored[..], 4)] {
+            let error = inflate(input, &mut Vec::new(), limit).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        }
+
+        // The limit is on everything a gzip file holds, not each member
+        let mut input = gzip(b"first member, ", None);
+        input.extend(gzip(b"second member", None));
+        assert_eq!(gunzip(&input, 27).unwrap().len(), 27);
+        let error = gunzip(&input, 26).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+    }
+}
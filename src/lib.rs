@@ -0,0 +1,24 @@
+//! A TFTP server and client, and the protocol they are built on.
+//!
+//! [tftp] has every packet type with a parser and serialiser, and [netascii] translates text
+//! transfers. [machine::Machine] tracks a transfer with a single client without doing any I/O
+//! of its own, and [session::Session] drives one against a [storage::FileProvider], the local
+//! filesystem unless the server is given another such as [storage::MemoryFs] or
+//! [archive::ArchiveFs]. [server] runs sessions over UDP sockets, one worker thread per
+//! transfer, and [client] downloads from and uploads to a server. [multicast] sends one file
+//! to many clients at once. [log] writes what the server is doing to stderr as text or JSON,
+//! and [metrics] counts it for Prometheus.
+
+pub mod archive;
+pub mod client;
+pub mod error;
+mod inflate;
+pub mod log;
+pub mod machine;
+pub mod metrics;
+pub mod multicast;
+pub mod netascii;
+pub mod server;
+pub mod session;
+pub mod storage;
+pub mod tftp;
@@ -0,0 +1,258 @@
+use std::{
+    fmt::{self, Write},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How important a log line is, each level includes everything above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// A transfer failed, or the server could not do something it needed to
+    Error,
+    /// Something odd that didn't stop a transfer, e.g. a packet from a stranger
+    Warn,
+    /// Every request, what was negotiated for it and how it ended
+    Info,
+    /// Every retransmit
+    Debug,
+}
+
+impl Level {
+    const ERROR: &str = "error";
+    const WARN: &str = "warn";
+    const INFO: &str = "info";
+    const DEBUG: &str = "debug";
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Error => Self::ERROR,
+            Self::Warn => Self::WARN,
+            Self::Info => Self::INFO,
+            Self::Debug => Self::DEBUG,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, String> {
+        [Self::Error, Self::Warn, Self::Info, Self::Debug]
+            .into_iter()
+            .find(|known| level.eq_ignore_ascii_case(known.name()))
+            .ok_or_else(|| "expected one of error, warn, info or debug".into())
+    }
+}
+
+/// What each log line looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A timestamp, the level and the message, followed by `key=value` fields
+    Text,
+    /// One JSON object per line, with the fields alongside `ts`, `level` and `msg`
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        if format.eq_ignore_ascii_case("text") {
+            Ok(Self::Text)
+        } else if format.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else {
+            Err("expected text or json".into())
+        }
+    }
+}
+
+/// The value of a field in a log line, numbers are left unquoted in JSON
+pub enum Value<'value> {
+    Text(&'value dyn fmt::Display),
+    Number(u64),
+}
+
+impl From<u64> for Value<'_> {
+    fn from(number: u64) -> Self {
+        Self::Number(number)
+    }
+}
+
+impl From<usize> for Value<'_> {
+    fn from(number: usize) -> Self {
+        Self::Number(number as u64)
+    }
+}
+
+/// Writes log lines at or above `level` to stderr, nothing at all if `level` is [None]
+#[derive(Debug, Clone, Copy)]
+pub struct Logger {
+    pub level: Option<Level>,
+    pub format: Format,
+}
+
+impl Default for Logger {
+    /// Warnings and errors as text
+    fn default() -> Self {
+        Self {
+            level: Some(Level::Warn),
+            format: Format::Text,
+        }
+    }
+}
+
+impl Logger {
+    /// Whether a line at `level` would be written, to skip any work that only goes into one
+    pub fn enabled(&self, level: Level) -> bool {
+        self.level.is_some_and(|enabled| level <= enabled)
+    }
+
+    /// Write `message` and its fields as one line, which is never interleaved with another
+    pub fn log(&self, level: Level, message: &str, fields: &[(&str, Value)]) {
+        if !self.enabled(level) {
+            return;
+        }
+
+        let line = match self.format {
+            Format::Text => text(level, message, fields),
+            Format::Json => json(level, message, fields),
+        };
+        eprintln!("{line}");
+    }
+}
+
+fn text(level: Level, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut line = format!(
+        "{} {:5} {message}",
+        timestamp(),
+        level.name().to_uppercase()
+    );
+    for (key, value) in fields {
+        match value {
+            Value::Number(number) => _ = write!(line, " {key}={number}"),
+            Value::Text(text) => {
+                let text = text.to_string();
+                // Quote anything that would otherwise be hard to split back up
+                if text.is_empty() || text.contains(|chr: char| chr.is_whitespace() || chr == '"') {
+                    _ = write!(line, " {key}={text:?}");
+                } else {
+                    _ = write!(line, " {key}={text}");
+                }
+            }
+        }
+    }
+    line
+}
+
+fn json(level: Level, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut line = String::from("{");
+    _ = write!(
+        line,
+        "\"ts\":\"{}\",\"level\":\"{}\",\"msg\":",
+        timestamp(),
+        level.name()
+    );
+    json_string(&mut line, message);
+    for (key, value) in fields {
+        line.push(',');
+        json_string(&mut line, key);
+        line.push(':');
+        match value {
+            Value::Number(number) => _ = write!(line, "{number}"),
+            Value::Text(text) => json_string(&mut line, &text.to_string()),
+        }
+    }
+    line.push('}');
+    line
+}
+
+fn json_string(line: &mut String, text: &str) {
+    line.push('"');
+    for chr in text.chars() {
+        match chr {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            chr if chr.is_control() => _ = write!(line, "\\u{:04x}", chr as u32),
+            chr => line.push(chr),
+        }
+    }
+    line.push('"');
+}
+
+/// The current time in UTC as RFC 3339, to the millisecond
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+/// The date `days` after 1970-01-01, from Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quoted(text: &str) -> String {
+        let mut line = String::new();
+        json_string(&mut line, text);
+        line
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(quoted(""), r#""""#);
+        assert_eq!(quoted(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quoted(r"boot\pxelinux.0"), r#""boot\\pxelinux.0""#);
+        assert_eq!(quoted("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(quoted("\0\u{1b}[31m\u{7f}"), r#""\u0000\u001b[31m\u007f""#);
+        assert_eq!(quoted("caf\u{e9} \u{1f600}"), "\"caf\u{e9} \u{1f600}\"");
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        // 2000 is a leap year as it divides by 400, 1900 and 2100 are not
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(-25_509), (1900, 2, 28));
+        assert_eq!(civil_from_days(-25_508), (1900, 3, 1));
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_453), (2025, 12, 31));
+    }
+}
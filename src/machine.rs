@@ -0,0 +1,1171 @@
+use crate::{
+    error::{Error, ProtocolError},
+    netascii::{self, Decoder, Encoder},
+    tftp::{
+        Acknowledgement, Data, Mode, OptionAcknowledgement, Request, Serialise, Tftp, TftpOption,
+    },
+};
+use std::{
+    fmt, io,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+/// Which way the file is moving, set by the request that started the [Machine]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read => write!(f, "read"),
+            Self::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Server wide settings that every [Machine] starts out with
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long we wait to hear from a client before retransmitting, unless the client
+    /// negotiates its own with the timeout option
+    pub timeout: Duration,
+    /// How many times we retransmit before giving up on a client
+    pub retries: usize,
+    /// How long a transfer can go without a block getting through before it is evicted, even
+    /// if the client is still sending us something
+    pub idle_timeout: Duration,
+    /// The largest block size we agree to, a client asking for more is offered this instead
+    pub max_block_size: usize,
+    /// The largest window we agree to, a client asking for more is offered this instead
+    pub max_window_size: usize,
+    /// Refuse every write request
+    pub read_only: bool,
+    /// The biggest file a client can upload, [None] for no limit
+    pub max_upload_size: Option<u64>,
+}
+
+impl Config {
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+    const DEFAULT_RETRIES: usize = 5;
+    /// Longer than the biggest timeout a client can negotiate, so one lost packet never
+    /// evicts a transfer
+    const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+    /// The largest block that fits in a UDP datagram along with the DATA header (RFC 2348)
+    pub const MAX_BLOCK_SIZE: usize = 65464;
+    pub const MAX_WINDOW_SIZE: usize = 65535;
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Self::DEFAULT_TIMEOUT,
+            retries: Self::DEFAULT_RETRIES,
+            idle_timeout: Self::DEFAULT_IDLE_TIMEOUT,
+            max_block_size: Self::MAX_BLOCK_SIZE,
+            max_window_size: Self::MAX_WINDOW_SIZE,
+            read_only: false,
+            max_upload_size: None,
+        }
+    }
+}
+
+/// Something that has happened to a transfer, given to [Machine::handle()]
+#[derive(Debug)]
+pub enum Input<'input> {
+    /// A datagram from the client
+    Packet(&'input [u8]),
+    /// Time has moved on, once the deadline has passed we retransmit
+    Tick,
+    /// The outcome of the last [Output::Storage] request
+    Storage(io::Result<Storage>),
+}
+
+/// Something the [Machine] needs whoever is driving it to do
+#[derive(Debug)]
+pub enum Output {
+    /// Send a packet to the client
+    Send(Vec<u8>),
+    /// Carry out a [StorageRequest] and hand back the result as an [Input::Storage]
+    Storage(StorageRequest),
+    /// Give the [Machine] an [Input::Tick] at this time if we have not heard from the client
+    Deadline(Instant),
+    /// The transfer has failed, any ERROR packet telling the client why is already queued
+    Failed(Error),
+    /// Something worth knowing about happened to the transfer, nothing needs doing
+    Event(Event),
+}
+
+/// The milestones of a transfer, for whoever is driving the [Machine] to log or count
+#[derive(Debug)]
+pub enum Event {
+    /// The client asked for a transfer, with the mode and options as it sent them
+    Request {
+        direction: Direction,
+        filename: String,
+        mode: String,
+        options: Vec<TftpOption>,
+    },
+    /// What we agreed to, whether or not there was an OACK. `transfer_size` is [None] for an
+    /// upload the client didn't give the size of
+    Negotiated {
+        block_size: usize,
+        window_size: usize,
+        timeout: Duration,
+        transfer_size: Option<u64>,
+        rollover: u16,
+    },
+    /// We heard nothing back by the deadline and sent our last packets again. `block` is the
+    /// last one acknowledged
+    Retransmit { block: u64, attempt: usize },
+    /// Every byte got through. `bytes` is the size of a file read as it was sent, netascii
+    /// included, and of an upload as it was stored
+    Completed {
+        direction: Direction,
+        bytes: u64,
+        duration: Duration,
+    },
+}
+
+/// File access the [Machine] needs, it never touches storage itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageRequest {
+    /// Open a file to be read, answered with [Storage::Opened]
+    OpenRead(String),
+    /// Create a file for an upload, answered with [Storage::Opened]. We never overwrite a file
+    /// that is already being served
+    OpenWrite(String),
+    /// Read `length` bytes from `offset` in the open file, answered with [Storage::Read]
+    Read { offset: u64, length: usize },
+    /// Write `data` at `offset` in the open file, answered with [Storage::Written]
+    Write { offset: u64, data: Vec<u8> },
+    /// The upload is complete and should be made durable, answered with [Storage::Committed]
+    Commit,
+    /// An upload that never finished should not leave a partial file behind, there is no
+    /// answer
+    Abort,
+}
+
+/// A [StorageRequest] that succeeded
+#[derive(Debug)]
+pub enum Storage {
+    /// The file is open, `size` is its length in bytes when reading
+    Opened {
+        size: u64,
+    },
+    /// The bytes asked for by [StorageRequest::Read]
+    Read(Vec<u8>),
+    Written,
+    Committed,
+}
+
+/// The [StorageRequest] we are waiting to hear back about
+#[derive(Debug)]
+enum Pending {
+    /// Opening the file, the options are negotiated once we know how big it is
+    Open(Vec<TftpOption>),
+    /// Reading through a file to be sent as netascii to find out how big it will be once
+    /// translated, `offset` is how far we have got through its `size` bytes
+    Measure {
+        options: Vec<TftpOption>,
+        offset: u64,
+        size: u64,
+        encoded: u64,
+    },
+    /// Reading the blocks from `first` to `last` so we can send them
+    Read {
+        first: u64,
+        last: u64,
+    },
+    /// Writing the last block we received, `acknowledge` if it ends a window
+    Write {
+        acknowledge: bool,
+        last: bool,
+    },
+    Commit,
+}
+
+/// Everything we know about a single transfer with a client, with no sockets, files or clocks
+/// of its own. Everything that happens to the transfer goes in through [Machine::handle()] and
+/// what it needs done comes out as [Output]s, so it can be driven by a blocking loop, an async
+/// runtime or a simulated network alike
+#[derive(Debug)]
+pub struct Machine {
+    config: Config,
+    /// The blocks of the current window, starting at [Machine::buffer_block]
+    buffer: Vec<u8>,
+    buffer_block: u64,
+    direction: Option<Direction>,
+    mode: Mode,
+    /// Translates a file being read in netascii mode
+    encoder: Option<Encoder>,
+    /// Translates a file being written in netascii mode
+    decoder: Option<Decoder>,
+    /// How many bytes of an upload have been written, after translation
+    written: u64,
+    /// An upload we have created a file for, which needs removing if it fails
+    opened: bool,
+    pending: Option<Pending>,
+    /// The last block acknowledged, counted from the start of the file so it never wraps
+    block: u64,
+    /// The last block of an upload we sent an ACK for, the next window starts after it
+    last_acknowledged: u64,
+    /// The last block whose ACK we answered with a window, and whether we have answered a
+    /// repeat of it too
+    answered: Option<u64>,
+    resent: bool,
+    /// What the on-wire block number wraps around to after 65535
+    rollover: u16,
+    block_size: usize,
+    file_size: usize,
+    window_size: usize,
+    timeout: Duration,
+    deadline: Option<Instant>,
+    /// When the transfer is evicted unless another block gets through first
+    idle_deadline: Option<Instant>,
+    /// When the request came in
+    started: Option<Instant>,
+    retransmits: usize,
+    last_sent: Vec<Vec<u8>>,
+    finished: bool,
+}
+
+/// An option whose value we can't work with
+fn out_of_range(option: &'static str, value: usize) -> Error {
+    ProtocolError::OptionOutOfRange { option, value }.into()
+}
+
+impl Machine {
+    const DEFAULT_BLOCK_SIZE: usize = 512;
+    const DEFAULT_WINDOW_SIZE: usize = 1;
+    const DEFAULT_ROLLOVER: u16 = 0;
+    /// The smallest block size RFC 2348 allows
+    const MIN_BLOCK_SIZE: usize = 8;
+    const TIMEOUT_RANGE: RangeInclusive<usize> = 1..=255;
+    const UTIMEOUT_RANGE: RangeInclusive<usize> = 10_000..=255_000_000;
+    const ROLLOVER_RANGE: RangeInclusive<usize> = 0..=1;
+    /// How much of a file we read at a time to find its netascii size
+    const MEASURE_CHUNK: u64 = 65536;
+
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            buffer: Vec::new(),
+            buffer_block: 1,
+            direction: None,
+            mode: Mode::Octet,
+            encoder: None,
+            decoder: None,
+            written: 0,
+            opened: false,
+            pending: None,
+            block: 0,
+            last_acknowledged: 0,
+            answered: None,
+            resent: false,
+            rollover: Self::DEFAULT_ROLLOVER,
+            block_size: Self::DEFAULT_BLOCK_SIZE,
+            file_size: 0,
+            window_size: Self::DEFAULT_WINDOW_SIZE,
+            timeout: config.timeout,
+            deadline: None,
+            idle_deadline: None,
+            started: None,
+            retransmits: 0,
+            last_sent: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Move the transfer on and return everything that needs doing as a result. Any [Error]
+    /// ends the transfer, it comes out as an [Output::Failed] after the ERROR packet for the
+    /// client
+    pub fn handle(&mut self, input: Input, now: Instant) -> Vec<Output> {
+        if self.finished {
+            return Vec::new();
+        }
+        let deadline = self.next_deadline();
+        let block = self.block;
+        let retransmit = matches!(input, Input::Tick);
+        let heard = matches!(input, Input::Packet(_));
+
+        let mut outputs = match input {
+            Input::Packet(data) => self.packet(data),
+            Input::Tick => self.tick(now),
+            Input::Storage(result) => self.storage(result),
+        }
+        .unwrap_or_else(|error| self.fail(error));
+
+        if self.started.is_none() && self.direction.is_some() {
+            self.started = Some(now);
+        }
+        if self.finished {
+            self.deadline = None;
+            self.idle_deadline = None;
+            if !outputs
+                .iter()
+                .any(|output| matches!(output, Output::Failed(_)))
+            {
+                outputs.push(self.completed(now));
+            }
+            return outputs;
+        }
+
+        // The first packet starts the transfer, after that only a new block counts as progress.
+        // An idle timeout too long to add to `now` never runs out
+        if self.idle_deadline.is_none() || self.block != block {
+            self.idle_deadline = now.checked_add(self.config.idle_timeout);
+        }
+
+        // Hearing from the client restarts the retransmit timer, anything new we are sending
+        // them is kept in case it needs to be sent again. A packet we ignore, like a repeated
+        // ACK, doesn't count or a client repeating itself could hold off our retransmit forever
+        let sent: Vec<Vec<u8>> = outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::Send(packet) => Some(packet.clone()),
+                _ => None,
+            })
+            .collect();
+        if !retransmit && ((heard && !outputs.is_empty()) || !sent.is_empty()) {
+            if !sent.is_empty() {
+                self.last_sent = sent;
+            }
+            self.retransmits = 0;
+            self.deadline = Some(now + self.timeout);
+        }
+
+        let next_deadline = self.next_deadline();
+        if let Some(new_deadline) = next_deadline.filter(|_| next_deadline != deadline) {
+            outputs.push(Output::Deadline(new_deadline));
+        }
+        outputs
+    }
+
+    /// When the [Machine] next needs an [Input::Tick], if we are waiting on the client
+    pub fn deadline(&self) -> Option<Instant> {
+        self.next_deadline()
+    }
+
+    /// Once the final block of a transfer has been sent or received there is nothing left for
+    /// this [Machine] to do and it can be dropped
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Whichever of the retransmit and idle deadlines comes first
+    fn next_deadline(&self) -> Option<Instant> {
+        match (self.deadline, self.idle_deadline) {
+            (Some(deadline), Some(idle_deadline)) => Some(deadline.min(idle_deadline)),
+            (deadline, idle_deadline) => deadline.or(idle_deadline),
+        }
+    }
+
+    fn packet(&mut self, data: &[u8]) -> Result<Vec<Output>, Error> {
+        match Tftp::parse(data)? {
+            Tftp::ReadRequest(req) => self.request(Direction::Read, req),
+            Tftp::WriteRequest(_) if self.config.read_only => Err(ProtocolError::ReadOnly.into()),
+            Tftp::WriteRequest(req) => self.request(Direction::Write, req),
+            // The client has given up on the transfer, there is nobody to reply to
+            Tftp::Error(error) => {
+                self.finished = true;
+                let mut outputs: Vec<Output> = self.abort().into_iter().collect();
+                outputs.push(Output::Failed(
+                    ProtocolError::Remote {
+                        code: error.code,
+                        message: error.message,
+                    }
+                    .into(),
+                ));
+                Ok(outputs)
+            }
+            Tftp::Acknowledgement(_) | Tftp::Data(_) if self.direction.is_none() => {
+                Err(ProtocolError::UnknownTransferId.into())
+            }
+            // Storage has not got back to us yet, whatever the client is repeating will be
+            // answered once it does
+            _ if self.pending.is_some() => Ok(Vec::new()),
+            Tftp::Acknowledgement(ack) if self.direction == Some(Direction::Read) => {
+                self.window(&ack)
+            }
+            Tftp::Data(data) if self.direction == Some(Direction::Write) => self.receive(&data),
+            Tftp::Acknowledgement(_) => Err(ProtocolError::UnexpectedPacket("ACK").into()),
+            Tftp::Data(_) => Err(ProtocolError::UnexpectedPacket("DATA").into()),
+            Tftp::OptionAcknowledgement(_) => Err(ProtocolError::UnexpectedPacket("OACK").into()),
+        }
+    }
+
+    /// Called regularly by the driver, if the client has not been heard from since the
+    /// deadline we resend whatever we sent them last. Once we run out of retries, or the
+    /// transfer has sat idle for too long, it is over
+    fn tick(&mut self, now: Instant) -> Result<Vec<Output>, Error> {
+        if self
+            .idle_deadline
+            .is_some_and(|idle_deadline| now >= idle_deadline)
+        {
+            return Err(ProtocolError::Idle(self.config.idle_timeout).into());
+        }
+
+        match self.deadline {
+            Some(deadline) if now >= deadline => {}
+            _ => return Ok(Vec::new()),
+        }
+
+        if self.retransmits >= self.config.retries {
+            return Err(ProtocolError::TimedOut {
+                retries: self.retransmits,
+            }
+            .into());
+        }
+        self.retransmits += 1;
+        self.deadline = Some(now + self.timeout);
+
+        let mut outputs = vec![Output::Event(Event::Retransmit {
+            block: self.block,
+            attempt: self.retransmits,
+        })];
+        outputs.extend(self.last_sent.iter().cloned().map(Output::Send));
+        Ok(outputs)
+    }
+
+    /// Carry on from wherever we were waiting on storage
+    fn storage(&mut self, result: io::Result<Storage>) -> Result<Vec<Output>, Error> {
+        match (self.pending.take(), result?) {
+            (Some(Pending::Open(options)), Storage::Opened { size }) => {
+                Ok(self.opened(options, size))
+            }
+            (
+                Some(Pending::Measure {
+                    options,
+                    offset,
+                    size,
+                    encoded,
+                }),
+                Storage::Read(data),
+            ) => {
+                if data.is_empty() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                self.measure(options, offset + data.len() as u64, size, encoded, &data)
+            }
+            (Some(Pending::Read { first, last }), Storage::Read(data)) => match &mut self.encoder {
+                Some(encoder) => {
+                    encoder.push(&data);
+                    self.read_window(first, last)
+                }
+                None => self.send_window(first, last, data),
+            },
+            (Some(Pending::Write { last: true, .. }), Storage::Written) => {
+                self.pending = Some(Pending::Commit);
+                Ok(vec![Output::Storage(StorageRequest::Commit)])
+            }
+            (Some(Pending::Write { acknowledge, .. }), Storage::Written) => Ok(acknowledge
+                .then(|| self.acknowledge())
+                .into_iter()
+                .collect()),
+            (Some(Pending::Commit), Storage::Committed) => {
+                self.finished = true;
+                Ok(vec![self.acknowledge()])
+            }
+            (_, result) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Storage result {result:?} was not asked for"),
+            )
+            .into()),
+        }
+    }
+
+    /// Start a new transfer, the file has to be opened before we can answer the client
+    fn request(&mut self, direction: Direction, req: Request) -> Result<Vec<Output>, Error> {
+        // A new request replaces whatever was in progress, an unfinished upload is removed
+        let mut outputs: Vec<Output> = self.abort().into_iter().collect();
+        *self = Self::new(&self.config);
+        self.direction = Some(direction);
+        outputs.push(Output::Event(Event::Request {
+            direction,
+            filename: req.filename.to_owned(),
+            mode: req.mode.to_owned(),
+            options: req.options.clone(),
+        }));
+
+        self.mode = match Mode::try_from(req.mode) {
+            Ok(mode) => mode,
+            Err(error) => {
+                outputs.extend(self.fail(error.into()));
+                return Ok(outputs);
+            }
+        };
+        if self.mode == Mode::Netascii {
+            match direction {
+                Direction::Read => self.encoder = Some(Encoder::new(0)),
+                Direction::Write => self.decoder = Some(Decoder::default()),
+            }
+        }
+        self.pending = Some(Pending::Open(req.options));
+
+        let filename = req.filename.to_owned();
+        outputs.push(Output::Storage(match direction {
+            Direction::Read => StorageRequest::OpenRead(filename),
+            Direction::Write => StorageRequest::OpenWrite(filename),
+        }));
+        Ok(outputs)
+    }
+
+    /// The file for the request is open, so we can confirm the options and start the transfer
+    fn opened(&mut self, options: Vec<TftpOption>, size: u64) -> Vec<Output> {
+        match self.direction {
+            Some(Direction::Write) => {
+                self.opened = true;
+
+                // On an upload the client tells us the size in the request
+                for option in &options {
+                    if let TftpOption::TransferSize(file_size) = option {
+                        self.file_size = *file_size;
+                    }
+                }
+            }
+            // Netascii gets bigger in translation, and we need to know by how much before we
+            // can tell the client the size or know which block is the last
+            _ if self.encoder.is_some() => {
+                self.encoder = Some(Encoder::new(size));
+                return self
+                    .measure(options, 0, size, 0, &[])
+                    .unwrap_or_else(|error| self.fail(error));
+            }
+            _ => self.file_size = size as usize,
+        }
+
+        self.confirm(&options)
+    }
+
+    /// Count up the netascii size of the file a chunk at a time, `offset` is how far we have
+    /// got including the chunk in `data`
+    fn measure(
+        &mut self,
+        options: Vec<TftpOption>,
+        offset: u64,
+        size: u64,
+        encoded: u64,
+        data: &[u8],
+    ) -> Result<Vec<Output>, Error> {
+        let encoded = encoded + netascii::encoded_len(data) as u64;
+        if offset >= size {
+            self.file_size = encoded as usize;
+            return Ok(self.confirm(&options));
+        }
+        let length = Self::MEASURE_CHUNK.min(size - offset) as usize;
+        self.pending = Some(Pending::Measure {
+            options,
+            offset,
+            size,
+            encoded,
+        });
+        Ok(vec![Output::Storage(StorageRequest::Read {
+            offset,
+            length,
+        })])
+    }
+
+    /// Send the OACK for the request, or fail if the options can't be agreed on. With nothing
+    /// to acknowledge we answer like an RFC 1350 server, older clients treat an empty OACK as
+    /// an illegal packet
+    fn confirm(&mut self, options: &[TftpOption]) -> Vec<Output> {
+        let result = self.negotiate(options).and_then(|oack| {
+            let mut outputs = vec![self.negotiated(options)];
+            if !oack.options.is_empty() {
+                outputs.push(Output::Send(Tftp::OptionAcknowledgement(oack).serialise()));
+                return Ok(outputs);
+            }
+            match self.direction {
+                Some(Direction::Write) => outputs.push(self.acknowledge()),
+                // The first window goes out as if the client had acknowledged an OACK
+                _ => outputs.extend(self.window(&Acknowledgement { block: 0 })?),
+            }
+            Ok(outputs)
+        });
+        result.unwrap_or_else(|error| self.fail(error))
+    }
+
+    fn negotiated(&self, options: &[TftpOption]) -> Output {
+        let transfer_size = match self.direction {
+            Some(Direction::Write) => options.iter().find_map(|option| match option {
+                TftpOption::TransferSize(size) => Some(*size as u64),
+                _ => None,
+            }),
+            _ => Some(self.file_size as u64),
+        };
+        Output::Event(Event::Negotiated {
+            block_size: self.block_size,
+            window_size: self.window_size,
+            timeout: self.timeout,
+            transfer_size,
+            rollover: self.rollover,
+        })
+    }
+
+    fn completed(&self, now: Instant) -> Output {
+        let direction = self.direction.unwrap_or(Direction::Read);
+        Output::Event(Event::Completed {
+            direction,
+            bytes: match direction {
+                Direction::Read => self.file_size as u64,
+                Direction::Write => self.written,
+            },
+            duration: self
+                .started
+                .map(|started| now.saturating_duration_since(started))
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Confirm options, anything we won't accept is left out so the client falls back to the
+    /// default. Block and window sizes over our limits are brought down to them, but the
+    /// client has to accept anything we offer so sizes too small to use, or an upload too big
+    /// to take, end the transfer (RFC 2347)
+    fn negotiate(&mut self, options: &[TftpOption]) -> Result<OptionAcknowledgement, Error> {
+        let mut accepted = Vec::with_capacity(options.len());
+
+        for option in options {
+            let option = match *option {
+                TftpOption::TransferSize(size)
+                    if self.direction == Some(Direction::Write)
+                        && self.config.max_upload_size.is_some_and(|max| size as u64 > max) =>
+                {
+                    return Err(out_of_range("tsize", size));
+                }
+                TftpOption::TransferSize(_) => TftpOption::TransferSize(self.file_size),
+                TftpOption::BlockSize(block_size) if block_size < Self::MIN_BLOCK_SIZE => {
+                    return Err(out_of_range("blksize", block_size));
+                }
+                TftpOption::BlockSize(block_size) => {
+                    self.block_size = block_size
+                        .min(self.config.max_block_size)
+                        .min(Config::MAX_BLOCK_SIZE);
+                    TftpOption::BlockSize(self.block_size)
+                }
+                TftpOption::WindowSize(0) => return Err(out_of_range("windowsize", 0)),
+                TftpOption::WindowSize(window_size) => {
+                    self.window_size = window_size
+                        .min(self.config.max_window_size)
+                        .min(Config::MAX_WINDOW_SIZE);
+                    TftpOption::WindowSize(self.window_size)
+                }
+                TftpOption::Timeout(timeout) if Self::TIMEOUT_RANGE.contains(&timeout) => {
+                    self.timeout = Duration::from_secs(timeout as u64);
+                    TftpOption::Timeout(timeout)
+                }
+                TftpOption::TimeoutMicros(timeout) if Self::UTIMEOUT_RANGE.contains(&timeout) => {
+                    self.timeout = Duration::from_micros(timeout as u64);
+                    TftpOption::TimeoutMicros(timeout)
+                }
+                TftpOption::Rollover(rollover) if Self::ROLLOVER_RANGE.contains(&rollover) => {
+                    self.rollover = rollover as u16;
+                    TftpOption::Rollover(rollover)
+                }
+                TftpOption::Timeout(_)
+                | TftpOption::TimeoutMicros(_)
+                | TftpOption::Rollover(_)
+                // Multicast transfers are run by the server, a client that asks for one here
+                // gets a unicast transfer instead (RFC 2090)
+                | TftpOption::Multicast(_)
+                | TftpOption::Unknown(..) => continue,
+            };
+            accepted.push(option);
+        }
+
+        Ok(OptionAcknowledgement { options: accepted })
+    }
+
+    /// RFC 7440 lets the client ask for a window of blocks per ACK, so we read every block
+    /// after the one acknowledged up to the end of the window. If the client acks a block in
+    /// the middle of the last window it lost something and we carry on from there
+    fn window(&mut self, ack: &Acknowledgement) -> Result<Vec<Output>, Error> {
+        // A late ACK from before the last one we saw, the client already has everything
+        // we would send in reply
+        let Some(block) = self.acknowledged(ack.block) else {
+            return Ok(Vec::new());
+        };
+
+        // The final block is always short, even if that means sending an empty one
+        let final_block = (self.file_size / self.block_size) as u64 + 1;
+
+        // We have received an ack for the final block so dont send
+        // more data
+        if block >= final_block {
+            self.finished = true;
+            return Ok(Vec::new());
+        }
+
+        // A repeat of the ACK we last answered. Answering every copy of it is the Sorcerer's
+        // Apprentice bug (RFC 1123 4.2.3.1), each duplicate doubles what we send from then on,
+        // so with one block per window we leave any loss to the retransmit timer. A bigger
+        // window is resent once per ACK, as it was most likely lost as a whole
+        if self.answered == Some(block) {
+            if self.window_size == 1 || self.resent {
+                return Ok(Vec::new());
+            }
+            self.resent = true;
+        } else {
+            self.resent = false;
+        }
+        self.answered = Some(block);
+        self.block = block;
+
+        let window_end = (block + self.window_size as u64).min(final_block);
+        self.read_window(block + 1, window_end)
+    }
+
+    /// Ask storage for the blocks from `first` to `last`, or send them if a netascii
+    /// translation already has them
+    fn read_window(&mut self, first: u64, last: u64) -> Result<Vec<Output>, Error> {
+        let start = (first - 1) * self.block_size as u64;
+        let end = (last * self.block_size as u64).min(self.file_size as u64);
+
+        let (offset, length) = match &mut self.encoder {
+            None => (start, (end - start) as usize),
+            Some(encoder) => match encoder.prepare(start, end) {
+                Some(read) => read,
+                None => {
+                    let data = encoder.window(start, end);
+                    return self.send_window(first, last, data);
+                }
+            },
+        };
+        self.pending = Some(Pending::Read { first, last });
+
+        Ok(vec![Output::Storage(StorageRequest::Read {
+            offset,
+            length,
+        })])
+    }
+
+    /// Send the window of blocks from `first` to `last` that storage has read for us
+    fn send_window(&mut self, first: u64, last: u64, data: Vec<u8>) -> Result<Vec<Output>, Error> {
+        let start = (first as usize - 1) * self.block_size;
+        let end = (last as usize * self.block_size).min(self.file_size);
+        if data.len() != end - start {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.buffer = data;
+        self.buffer_block = first;
+
+        Ok((first..=last)
+            .map(|block| Output::Send(Tftp::Data(self.data(block)).serialise()))
+            .collect())
+    }
+
+    /// A DATA packet for a block in the current window
+    fn data(&self, block: u64) -> Data<'_> {
+        let current_slice = (block - self.buffer_block) as usize * self.block_size;
+        let current_slice_end = (current_slice + self.block_size).min(self.buffer.len());
+
+        Data {
+            block: self.wire_block(block),
+            data: &self.buffer[current_slice..current_slice_end],
+        }
+    }
+
+    /// Store the next block of an upload, we only acknowledge at the end of each window or
+    /// when the client has sent the final (short) block. Anything out of order gets an ack for
+    /// the last block we have so the client can resend from there, and the window after that
+    /// ack counts from it rather than from the start of the file
+    fn receive(&mut self, data: &Data) -> Result<Vec<Output>, Error> {
+        if data.data.len() > self.block_size {
+            return Err(ProtocolError::BlockTooLarge {
+                block_size: self.block_size,
+                actual: data.data.len(),
+            }
+            .into());
+        }
+
+        if data.block != self.wire_block(self.block + 1) {
+            return Ok(vec![self.acknowledge()]);
+        }
+
+        let last = data.data.len() < self.block_size;
+        let data = match &mut self.decoder {
+            Some(decoder) if last => [decoder.decode(data.data), decoder.finish()].concat(),
+            Some(decoder) => decoder.decode(data.data),
+            None => data.data.to_vec(),
+        };
+        let offset = self.written;
+        self.written += data.len() as u64;
+        if self
+            .config
+            .max_upload_size
+            .is_some_and(|max| self.written > max)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "Upload is bigger than the server allows",
+            )
+            .into());
+        }
+        self.block += 1;
+        self.pending = Some(Pending::Write {
+            acknowledge: self.block - self.last_acknowledged == self.window_size as u64,
+            last,
+        });
+
+        Ok(vec![Output::Storage(StorageRequest::Write {
+            offset,
+            data,
+        })])
+    }
+
+    /// An ACK for the last block we have received, which starts the next window
+    fn acknowledge(&mut self) -> Output {
+        self.last_acknowledged = self.block;
+        let ack = Acknowledgement {
+            block: self.wire_block(self.block),
+        };
+        Output::Send(Tftp::Acknowledgement(ack).serialise())
+    }
+
+    /// Any error we send ends the transfer
+    fn fail(&mut self, error: Error) -> Vec<Output> {
+        let mut outputs = vec![Output::Send(Tftp::serialise_error(&error))];
+        outputs.extend(self.abort());
+        outputs.push(Output::Failed(error));
+        self.finished = true;
+        outputs
+    }
+
+    /// Ask for a partial upload to be removed, as long as we were the ones that created it
+    fn abort(&mut self) -> Option<Output> {
+        if self.direction != Some(Direction::Write) || !self.opened {
+            return None;
+        }
+        self.opened = false;
+        Some(Output::Storage(StorageRequest::Abort))
+    }
+
+    /// The block number we put on the wire for a block, once we go past 65535 it wraps back
+    /// around to [Machine::rollover]
+    fn wire_block(&self, block: u64) -> u16 {
+        match u16::try_from(block) {
+            Ok(block) => block,
+            Err(_) => {
+                let period = (u16::MAX - self.rollover) as u64 + 1;
+                ((block - u16::MAX as u64 - 1) % period) as u16 + self.rollover
+            }
+        }
+    }
+
+    /// Work out which block an ACK is for. It can only be for a block we have sent since the
+    /// last ACK, anything else is late and we ignore it
+    fn acknowledged(&self, wire_block: u16) -> Option<u64> {
+        (self.block..=self.block + self.window_size as u64)
+            .find(|block| self.wire_block(*block) == wire_block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::ErrorCode;
+
+    /// Feed `input` to the machine, answering every storage request from `file`, and return
+    /// the packets it sends
+    fn drive(machine: &mut Machine, input: Input, file: &[u8]) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut outputs = machine.handle(input, now);
+        let mut sent = Vec::new();
+
+        while !outputs.is_empty() {
+            let mut next = Vec::new();
+            for output in outputs {
+                let result = match output {
+                    Output::Send(packet) => {
+                        sent.push(packet);
+                        continue;
+                    }
+                    Output::Storage(StorageRequest::OpenRead(_)) => Ok(Storage::Opened {
+                        size: file.len() as u64,
+                    }),
+                    Output::Storage(StorageRequest::Read { offset, length }) => {
+                        let offset = offset as usize;
+                        Ok(Storage::Read(file[offset..offset + length].to_vec()))
+                    }
+                    Output::Storage(StorageRequest::OpenWrite(_)) => {
+                        Ok(Storage::Opened { size: 0 })
+                    }
+                    Output::Storage(StorageRequest::Write { .. }) => Ok(Storage::Written),
+                    Output::Storage(StorageRequest::Commit) => Ok(Storage::Committed),
+                    _ => continue,
+                };
+                next.extend(machine.handle(Input::Storage(result), now));
+            }
+            outputs = next;
+        }
+        sent
+    }
+
+    fn read(file: &[u8], options: Vec<TftpOption>) -> (Machine, Vec<Vec<u8>>) {
+        let mut machine = Machine::new(&Config::default());
+        let request = Tftp::ReadRequest(Request {
+            filename: "pxelinux.0",
+            mode: "octet",
+            options,
+        })
+        .serialise();
+        let sent = drive(&mut machine, Input::Packet(&request), file);
+        (machine, sent)
+    }
+
+    fn ack(machine: &mut Machine, block: u16, file: &[u8]) -> Vec<Vec<u8>> {
+        let ack = Tftp::Acknowledgement(Acknowledgement { block }).serialise();
+        drive(machine, Input::Packet(&ack), file)
+    }
+
+    fn write(options: Vec<TftpOption>) -> Machine {
+        let mut machine = Machine::new(&Config::default());
+        let request = Tftp::WriteRequest(Request {
+            filename: "upload.bin",
+            mode: "octet",
+            options,
+        })
+        .serialise();
+        drive(&mut machine, Input::Packet(&request), &[]);
+        machine
+    }
+
+    /// Send DATA `block` of `length` bytes and return the block numbers of any ACKs
+    fn data(machine: &mut Machine, block: u16, length: usize) -> Vec<u16> {
+        let payload = vec![7; length];
+        let data = Tftp::Data(Data {
+            block,
+            data: &payload,
+        })
+        .serialise();
+        drive(machine, Input::Packet(&data), &[])
+            .iter()
+            .map(|packet| match Tftp::parse(packet) {
+                Ok(Tftp::Acknowledgement(ack)) => ack.block,
+                packet => panic!("expected ACK, got {packet:?}"),
+            })
+            .collect()
+    }
+
+    /// The block number and length of every DATA packet
+    fn blocks(sent: &[Vec<u8>]) -> Vec<(u16, usize)> {
+        sent.iter()
+            .map(|packet| match Tftp::parse(packet) {
+                Ok(Tftp::Data(data)) => (data.block, data.data.len()),
+                packet => panic!("expected DATA, got {packet:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sends_a_full_window_per_ack() {
+        let file = vec![7; 3000];
+        let (mut machine, sent) = read(&file, vec![TftpOption::WindowSize(4)]);
+        assert!(matches!(
+            Tftp::parse(&sent[0]),
+            Ok(Tftp::OptionAcknowledgement(_))
+        ));
+
+        let sent = ack(&mut machine, 0, &file);
+        assert_eq!(blocks(&sent), [(1, 512), (2, 512), (3, 512), (4, 512)]);
+        let sent = ack(&mut machine, 4, &file);
+        assert_eq!(blocks(&sent), [(5, 512), (6, 440)]);
+        assert!(ack(&mut machine, 6, &file).is_empty());
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn carries_on_from_an_ack_mid_window() {
+        let file = vec![7; 3000];
+        let (mut machine, _) = read(&file, vec![TftpOption::WindowSize(4)]);
+        ack(&mut machine, 0, &file);
+
+        let sent = ack(&mut machine, 2, &file);
+        assert_eq!(blocks(&sent), [(3, 512), (4, 512), (5, 512), (6, 440)]);
+    }
+
+    #[test]
+    fn ignores_a_duplicate_ack_with_one_block_per_window() {
+        let file = vec![7; 3000];
+        let (mut machine, _) = read(&file, Vec::new());
+        assert_eq!(blocks(&ack(&mut machine, 1, &file)), [(2, 512)]);
+        assert!(ack(&mut machine, 1, &file).is_empty());
+        assert!(ack(&mut machine, 1, &file).is_empty());
+        assert_eq!(blocks(&ack(&mut machine, 2, &file)), [(3, 512)]);
+    }
+
+    #[test]
+    fn resends_a_window_once_for_a_duplicate_ack() {
+        let file = vec![7; 3000];
+        let (mut machine, _) = read(&file, vec![TftpOption::WindowSize(4)]);
+        ack(&mut machine, 0, &file);
+
+        let sent = ack(&mut machine, 0, &file);
+        assert_eq!(blocks(&sent), [(1, 512), (2, 512), (3, 512), (4, 512)]);
+        assert!(ack(&mut machine, 0, &file).is_empty());
+    }
+
+    #[test]
+    fn ends_with_a_short_block() {
+        let file = vec![7; 1000];
+        let (mut machine, sent) = read(&file, vec![TftpOption::WindowSize(8)]);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(blocks(&ack(&mut machine, 0, &file)), [(1, 512), (2, 488)]);
+        assert!(!machine.is_finished());
+        ack(&mut machine, 2, &file);
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn sends_an_empty_final_block_for_a_whole_number_of_blocks() {
+        let file = vec![7; 1024];
+        let (mut machine, _) = read(&file, vec![TftpOption::WindowSize(8)]);
+        assert_eq!(
+            blocks(&ack(&mut machine, 0, &file)),
+            [(1, 512), (2, 512), (3, 0)]
+        );
+        ack(&mut machine, 3, &file);
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn counts_the_next_window_from_the_ack_for_a_lost_block() {
+        let mut machine = write(vec![TftpOption::WindowSize(4)]);
+        assert!(data(&mut machine, 1, 512).is_empty());
+        assert!(data(&mut machine, 2, 512).is_empty());
+        // Block 3 goes missing, so 4 gets the ACK the client resends from
+        assert_eq!(data(&mut machine, 4, 512), [2]);
+
+        assert!(data(&mut machine, 3, 512).is_empty());
+        assert!(data(&mut machine, 4, 512).is_empty());
+        assert!(data(&mut machine, 5, 512).is_empty());
+        assert_eq!(data(&mut machine, 6, 512), [6]);
+        assert_eq!(data(&mut machine, 7, 100), [7]);
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn aborts_an_upload_replaced_by_a_new_request() {
+        let mut machine = write(Vec::new());
+        assert_eq!(data(&mut machine, 1, 512), [1]);
+
+        let request = Tftp::WriteRequest(Request {
+            filename: "other.bin",
+            mode: "octet",
+            options: Vec::new(),
+        })
+        .serialise();
+        let outputs = machine.handle(Input::Packet(&request), Instant::now());
+        assert!(matches!(
+            outputs[..],
+            [
+                Output::Storage(StorageRequest::Abort),
+                Output::Event(_),
+                Output::Storage(StorageRequest::OpenWrite(_)),
+                ..
+            ]
+        ));
+    }
+
+    #[test]
+    fn brings_sizes_down_to_the_configured_maximums() {
+        let config = Config {
+            max_block_size: 1024,
+            max_window_size: 4,
+            ..Config::default()
+        };
+        let mut machine = Machine::new(&config);
+        let oack = machine
+            .negotiate(&[TftpOption::BlockSize(1428), TftpOption::WindowSize(64)])
+            .unwrap();
+        assert_eq!(
+            oack.options,
+            [TftpOption::BlockSize(1024), TftpOption::WindowSize(4)]
+        );
+        assert_eq!((machine.block_size, machine.window_size), (1024, 4));
+
+        // Sizes within the limits are taken as they are, and the limits can't go past what
+        // the protocol allows
+        let mut machine = Machine::new(&Config::default());
+        let oack = machine
+            .negotiate(&[
+                TftpOption::BlockSize(Config::MAX_BLOCK_SIZE + 1),
+                TftpOption::WindowSize(8),
+            ])
+            .unwrap();
+        assert_eq!(
+            oack.options,
+            [
+                TftpOption::BlockSize(Config::MAX_BLOCK_SIZE),
+                TftpOption::WindowSize(8)
+            ]
+        );
+    }
+
+    #[test]
+    fn ends_the_transfer_for_sizes_too_small_to_use() {
+        for option in [
+            TftpOption::BlockSize(7),
+            TftpOption::BlockSize(0),
+            TftpOption::WindowSize(0),
+        ] {
+            let (machine, sent) = read(&[7; 100], vec![option.clone()]);
+            match Tftp::parse(&sent[0]) {
+                Ok(Tftp::Error(error)) => assert_eq!(error.code, ErrorCode::OptionNegotiation),
+                packet => panic!("expected ERROR for {option}, got {packet:?}"),
+            }
+            assert!(machine.is_finished());
+        }
+
+        let (_, sent) = read(&[7; 100], vec![TftpOption::BlockSize(8)]);
+        let Ok(Tftp::OptionAcknowledgement(oack)) = Tftp::parse(&sent[0]) else {
+            panic!("expected an OACK");
+        };
+        assert_eq!(oack.options, [TftpOption::BlockSize(8)]);
+    }
+
+    #[test]
+    fn wraps_block_numbers_to_the_rollover() {
+        let mut machine = Machine::new(&Config::default());
+        for (block, wire) in [
+            (1, 1),
+            (65535, 65535),
+            (65536, 0),
+            (65537, 1),
+            (131_071, 65535),
+            (131_072, 0),
+        ] {
+            assert_eq!(machine.wire_block(block), wire, "block {block}");
+        }
+
+        machine.rollover = 1;
+        for (block, wire) in [
+            (65535, 65535),
+            (65536, 1),
+            (65537, 2),
+            (131_070, 65535),
+            (131_071, 1),
+        ] {
+            assert_eq!(machine.wire_block(block), wire, "block {block}");
+        }
+    }
+
+    #[test]
+    fn follows_acks_across_the_rollover() {
+        let mut machine = Machine::new(&Config::default());
+        machine.block = 65534;
+        machine.window_size = 4;
+        assert_eq!(machine.acknowledged(65535), Some(65535));
+        assert_eq!(machine.acknowledged(0), Some(65536));
+        assert_eq!(machine.acknowledged(2), Some(65538));
+        assert_eq!(machine.acknowledged(3), None);
+
+        machine.rollover = 1;
+        assert_eq!(machine.acknowledged(1), Some(65536));
+        assert_eq!(machine.acknowledged(3), Some(65538));
+        assert_eq!(machine.acknowledged(0), None);
+    }
+}
@@ -1,46 +1,154 @@
+mod cli;
+
+use cli::{Command, Serve, Transfer};
 use std::{
-    collections::HashMap,
-    net::{SocketAddr, UdpSocket},
-    sync::{Arc, Mutex},
+    env,
+    fs::{self, File},
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    path::Path,
+    process,
+};
+use tftp3o::{
+    archive::ArchiveFs,
+    client::{Client, Progress},
+    error::Error,
+    server::Server,
+    storage::MemoryFs,
 };
 
 const PORT: u16 = 69;
-const UDP_BUFFER_SIZE: usize = 512;
-const BIND_ADDR: &str = "0.0.0.0";
-
-mod error;
-mod tftp;
-use tftp::{Session, Tftp};
-
-type TftpSessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
 
 fn main() {
-    let socket = UdpSocket::bind((BIND_ADDR, PORT)).unwrap();
-    let sessions: TftpSessions = Arc::new(Mutex::new(HashMap::new()));
+    let command = cli::parse(env::args().skip(1)).unwrap_or_else(|message| {
+        eprintln!("tftp3o: {message}\nTry 'tftp3o --help' for more information");
+        process::exit(2);
+    });
 
-    loop {
-        let mut buffer = [0u8; UDP_BUFFER_SIZE];
+    let result = match command {
+        Command::Serve(serve) => run(serve),
+        Command::Get(transfer) => get(transfer),
+        Command::Put(transfer) => put(transfer),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
+    };
+    if let Err(error) = result {
+        eprintln!("tftp3o: {error}");
+        process::exit(1);
+    }
+}
 
-        match socket.recv_from(&mut buffer) {
-            Ok((len, client)) => handle(&socket, &client, sessions.clone(), &buffer[..len]),
-            Err(_) => todo!(),
+fn run(serve: Serve) -> Result<(), Error> {
+    let addrs: Vec<SocketAddr> = serve
+        .listen
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, serve.port))
+        .collect();
+    let mut server = Server::bind(&addrs[..], serve.config)?.logger(serve.logger);
+    if !serve.archives.is_empty() {
+        let mut archives = ArchiveFs::new();
+        if let Some(max_inflated_size) = serve.max_inflated_size {
+            archives = archives.max_inflated_size(max_inflated_size);
+        }
+        for archive in &serve.archives {
+            archives.mount(archive)?;
         }
+        server = server.storage(archives);
+    } else if serve.in_memory {
+        server = server.storage(MemoryFs::snapshot(&serve.root, serve.logger)?);
+    } else {
+        server = server.root(serve.root);
     }
+    if let Some(ports) = serve.transfer_ports {
+        server = server.transfer_ports(ports);
+    }
+    if let Some(max_sessions) = serve.max_sessions {
+        server = server.max_sessions(max_sessions);
+    }
+    if let Some(max_client_sessions) = serve.max_client_sessions {
+        server = server.max_client_sessions(max_client_sessions);
+    }
+    if let Some(first) = serve.multicast {
+        server = server.multicast(first);
+    }
+    if let Some(addr) = serve.metrics {
+        server = server.metrics_listener(TcpListener::bind(addr)?);
+    }
+
+    server.run()
 }
 
-/// Once a [UdpSocket] has been accept we check to see if we already have an open [Session] with
-/// that socket, if we don't we create a branch new [Session] to store the state of our transaction
-/// with a client. If we do have a [Session] we carry on where we left off.
-///
-/// We take the data from the socket and the client [Session] and pass to [Tftp::handle()]. If the
-/// data from a client is valid and implemented by us we respond correctly, else we silently drop
-/// the client from out list of [TftpSessions]
-fn handle(socket: &UdpSocket, client: &SocketAddr, sessions: TftpSessions, data: &[u8]) {
-    let mut sessions = sessions.lock().unwrap();
-    let mut session = sessions.entry(*client).or_insert(Session::new());
+fn get(transfer: Transfer) -> Result<(), Error> {
+    let client = client(&transfer)?;
+    let remote = &transfer.source;
+    let local = transfer.destination.as_deref().unwrap_or(file_name(remote));
 
-    match Tftp::handle(&mut session, data) {
-        Some(response) => _ = socket.send_to(&response, client).unwrap(),
-        None => _ = sessions.remove(client).unwrap(),
-    };
+    let file = File::create_new(local)?;
+    let result = client.get(remote, file, report);
+    eprintln!();
+    if result.is_err() {
+        _ = fs::remove_file(local);
+    }
+    result.map(|_| ())
+}
+
+fn put(transfer: Transfer) -> Result<(), Error> {
+    let client = client(&transfer)?;
+    let local = &transfer.source;
+    let remote = transfer.destination.as_deref().unwrap_or(file_name(local));
+
+    let file = File::open(local)?;
+    let size = file.metadata()?.len();
+    let result = client.put(remote, file, Some(size), report);
+    eprintln!();
+    result.map(|_| ())
+}
+
+/// A [Client] for the server in `transfer`, asking for the options it was given
+fn client(transfer: &Transfer) -> io::Result<Client> {
+    let mut client = Client::connect(server_addr(&transfer.server)?)?;
+    if let Some(block_size) = transfer.block_size {
+        client = client.block_size(block_size);
+    }
+    if let Some(window_size) = transfer.window_size {
+        client = client.window_size(window_size);
+    }
+    if let Some(timeout) = transfer.timeout {
+        client = client.timeout(timeout);
+    }
+    if let Some(retries) = transfer.retries {
+        client = client.retries(retries);
+    }
+
+    Ok(client)
+}
+
+/// A server given as just a host gets the standard TFTP port
+fn server_addr(server: &str) -> io::Result<SocketAddr> {
+    server
+        .to_socket_addrs()
+        .or_else(|_| (server, PORT).to_socket_addrs())?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address for server"))
+}
+
+/// Default to the same name as the other end, without any directories it is in
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+fn report(progress: Progress) {
+    match progress.total {
+        Some(total) if total > 0 => eprint!(
+            "\r{} / {total} bytes ({}%)",
+            progress.bytes,
+            progress.bytes * 100 / total
+        ),
+        _ => eprint!("\r{} bytes", progress.bytes),
+    }
 }
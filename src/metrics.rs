@@ -0,0 +1,402 @@
+use crate::machine::Direction;
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// The opcode labels, indexed by opcode with anything that isn't one counted under 0
+const OPCODES: [&str; 7] = ["invalid", "RRQ", "WRQ", "DATA", "ACK", "ERROR", "OACK"];
+/// Every error code from RFC 1350 and RFC 2347
+const ERROR_CODES: usize = 9;
+const BLOCK_SIZE_BUCKETS: &[f64] = &[
+    512.0, 1024.0, 1428.0, 1468.0, 2048.0, 4096.0, 8192.0, 16384.0, 32768.0, 65464.0,
+];
+const WINDOW_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+/// How long we give a scraper to send its request before moving on to the next one
+const HTTP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counts of everything the server has done since it started, rendered in the Prometheus text
+/// format by [Metrics::render()]. Every method only touches atomics so any thread can record
+/// without waiting on another
+#[derive(Debug)]
+pub struct Metrics {
+    packets_received: [AtomicU64; OPCODES.len()],
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    completed: [AtomicU64; 2],
+    failed: [AtomicU64; ERROR_CODES],
+    retransmits: AtomicU64,
+    active: AtomicUsize,
+    block_size: Histogram,
+    window_size: Histogram,
+    duration: Histogram,
+}
+
+/// Observations counted into cumulative buckets, with a sum of every value observed
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// One more than there are bounds, the last is +Inf
+    buckets: Vec<AtomicU64>,
+    /// An f64 stored as its bits
+    sum: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            packets_received: Default::default(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            completed: Default::default(),
+            failed: Default::default(),
+            retransmits: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            block_size: Histogram::new(BLOCK_SIZE_BUCKETS),
+            window_size: Histogram::new(WINDOW_SIZE_BUCKETS),
+            duration: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// A datagram from a client, counted by the opcode in its first two bytes
+    pub fn received(&self, packet: &[u8]) {
+        let opcode = match packet {
+            [0, opcode @ 1..=6, ..] => *opcode as usize,
+            _ => 0,
+        };
+        self.packets_received[opcode].fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A transfer has started, it stays active until [Metrics::ended()]
+    pub fn started(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ended(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn negotiated(&self, block_size: usize, window_size: usize) {
+        self.block_size.observe(block_size as f64);
+        self.window_size.observe(window_size as f64);
+    }
+
+    pub fn retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn completed(&self, direction: Direction, duration: Duration) {
+        self.completed[direction as usize].fetch_add(1, Ordering::Relaxed);
+        self.duration.observe(duration.as_secs_f64());
+    }
+
+    /// A transfer ended with the ERROR `code`, whichever end sent it
+    pub fn failed(&self, code: u16) {
+        let code = (code as usize).min(ERROR_CODES - 1);
+        self.failed[code].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything counted so far in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "tftp_packets_received_total",
+            "counter",
+            "Packets received from clients, by opcode",
+        );
+        for (name, count) in OPCODES.iter().zip(&self.packets_received) {
+            let count = count.load(Ordering::Relaxed);
+            _ = writeln!(
+                out,
+                "tftp_packets_received_total{{opcode=\"{name}\"}} {count}"
+            );
+        }
+
+        counter(
+            &mut out,
+            "tftp_received_bytes_total",
+            "Bytes received from clients, headers included",
+            &self.bytes_received,
+        );
+        counter(
+            &mut out,
+            "tftp_sent_bytes_total",
+            "Bytes sent to clients, headers included",
+            &self.bytes_sent,
+        );
+
+        header(
+            &mut out,
+            "tftp_transfers_completed_total",
+            "counter",
+            "Transfers that got every block through, by direction",
+        );
+        for (direction, count) in [Direction::Read, Direction::Write]
+            .iter()
+            .zip(&self.completed)
+        {
+            let count = count.load(Ordering::Relaxed);
+            _ = writeln!(
+                out,
+                "tftp_transfers_completed_total{{direction=\"{direction}\"}} {count}"
+            );
+        }
+
+        header(
+            &mut out,
+            "tftp_transfers_failed_total",
+            "counter",
+            "Transfers ended by an ERROR from either end, by error code",
+        );
+        for (code, count) in self.failed.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            _ = writeln!(
+                out,
+                "tftp_transfers_failed_total{{code=\"{code}\"}} {count}"
+            );
+        }
+
+        counter(
+            &mut out,
+            "tftp_retransmits_total",
+            "Times we resent packets because the client went quiet",
+            &self.retransmits,
+        );
+
+        header(
+            &mut out,
+            "tftp_active_sessions",
+            "gauge",
+            "Transfers in progress",
+        );
+        let active = self.active.load(Ordering::Relaxed);
+        _ = writeln!(out, "tftp_active_sessions {active}");
+
+        self.block_size.render(
+            &mut out,
+            "tftp_negotiated_blksize_bytes",
+            "Block size agreed for each transfer",
+        );
+        self.window_size.render(
+            &mut out,
+            "tftp_negotiated_windowsize_blocks",
+            "Window size agreed for each transfer",
+        );
+        self.duration.render(
+            &mut out,
+            "tftp_transfer_duration_seconds",
+            "How long completed transfers took from request to last block",
+        );
+
+        out
+    }
+
+    /// Answer every `GET /metrics` on `listener` with [Metrics::render()], forever. Scrapers
+    /// are answered one at a time, so a slow one only holds things up until it times out
+    pub fn serve(&self, listener: &TcpListener) {
+        // A scraper that went away or sent nothing useful has nothing to be told
+        for stream in listener.incoming().flatten() {
+            _ = self.respond(stream);
+        }
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+        // Only the request line matters, and it always fits in the first read
+        let mut buffer = [0; 1024];
+        let len = stream.read(&mut buffer)?;
+        let request = String::from_utf8_lossy(&buffer[..len]);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+        let (status, body) = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "Not found\n".into()),
+            _ => ("405 Method Not Allowed", "Method not allowed\n".into()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+    }
+
+    /// Prometheus buckets are cumulative, each counts everything at or below its bound
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "histogram", help);
+        let mut count = 0;
+        for (bucket, observed) in self.buckets.iter().enumerate() {
+            count += observed.load(Ordering::Relaxed);
+            match self.bounds.get(bucket) {
+                Some(bound) => _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}"),
+                None => _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}"),
+            }
+        }
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        _ = writeln!(out, "{name}_sum {sum}");
+        _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of the sample `series`, which is its name and any labels
+    fn sample(rendered: &str, series: &str) -> String {
+        rendered
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no sample for {series}"))
+            .to_owned()
+    }
+
+    #[test]
+    fn renders_the_text_exposition_format() {
+        let metrics = Metrics::default();
+        metrics.received(&[0, 1, b'f', 0]);
+        metrics.received(&[0, 4, 0, 1]);
+        metrics.received(&[0, 9]);
+        metrics.sent(516);
+        metrics.started();
+        metrics.failed(1);
+        metrics.failed(40);
+        metrics.completed(Direction::Write, Duration::from_millis(250));
+        let rendered = metrics.render();
+
+        // Every family has its HELP and TYPE before any of its samples, and every sample
+        // belongs to the family above it
+        let mut family = "";
+        for line in rendered.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                family = help.split(' ').next().unwrap();
+                continue;
+            }
+            if let Some(kind) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = kind.split_once(' ').unwrap();
+                assert_eq!(name, family);
+                assert!(["counter", "gauge", "histogram"].contains(&kind), "{line}");
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let name = series.split('{').next().unwrap();
+            let suffix = name
+                .strip_prefix(family)
+                .unwrap_or_else(|| panic!("{line}"));
+            assert!(
+                ["", "_bucket", "_sum", "_count"].contains(&suffix),
+                "{line}"
+            );
+            assert!(value.parse::<f64>().is_ok(), "{line}");
+        }
+
+        assert_eq!(
+            sample(&rendered, r#"tftp_packets_received_total{opcode="RRQ"}"#),
+            "1"
+        );
+        assert_eq!(
+            sample(&rendered, r#"tftp_packets_received_total{opcode="ACK"}"#),
+            "1"
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                r#"tftp_packets_received_total{opcode="invalid"}"#
+            ),
+            "1"
+        );
+        assert_eq!(sample(&rendered, "tftp_received_bytes_total"), "10");
+        assert_eq!(sample(&rendered, "tftp_sent_bytes_total"), "516");
+        assert_eq!(sample(&rendered, "tftp_active_sessions"), "1");
+        assert_eq!(
+            sample(&rendered, r#"tftp_transfers_failed_total{code="1"}"#),
+            "1"
+        );
+        // Codes past the ones we know are counted under the last
+        assert_eq!(
+            sample(&rendered, r#"tftp_transfers_failed_total{code="8"}"#),
+            "1"
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                r#"tftp_transfers_completed_total{direction="write"}"#
+            ),
+            "1"
+        );
+    }
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let histogram = Histogram::new(&[1.0, 2.5, 10.0]);
+        for value in [0.5, 1.0, 2.0, 7.5, 100.0] {
+            histogram.observe(value);
+        }
+        let mut rendered = String::new();
+        histogram.render(&mut rendered, "test_seconds", "A test");
+
+        assert_eq!(
+            rendered,
+            "# HELP test_seconds A test\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"1\"} 2\n\
+             test_seconds_bucket{le=\"2.5\"} 3\n\
+             test_seconds_bucket{le=\"10\"} 4\n\
+             test_seconds_bucket{le=\"+Inf\"} 5\n\
+             test_seconds_sum 111\n\
+             test_seconds_count 5\n"
+        );
+    }
+}
@@ -0,0 +1,532 @@
+use crate::{
+    error::{Error, ProtocolError},
+    machine::Config,
+    storage::ReadFile,
+    tftp::{Data, Mode, OptionAcknowledgement, Request, Serialise, Tftp, TftpOption},
+};
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+/// Something the driver of a [Group] needs to do, or might want to log
+#[derive(Debug)]
+pub enum Action {
+    /// Send a packet to a client, or to the whole group at [Group::address()]
+    Send(SocketAddr, Vec<u8>),
+    /// A client joined the group, as its master if it was the only one there
+    Joined { client: SocketAddr, master: bool },
+    /// A client has been made master now the one before it has gone
+    Master(SocketAddr),
+    /// The master acknowledged the final block, so it has the whole file and leaves
+    Completed {
+        client: SocketAddr,
+        bytes: u64,
+        duration: Duration,
+    },
+    /// A client was dropped from the group, any ERROR telling it why is already queued
+    Failed(SocketAddr, Error),
+}
+
+/// A client in the group, the first one is the master
+#[derive(Debug)]
+struct Member {
+    addr: SocketAddr,
+    /// Everything but the multicast option it gets in its OACK
+    options: Vec<TftpOption>,
+    joined: Instant,
+}
+
+/// Whether a read request asks for multicast in a way we can serve. Netascii is translated
+/// per client so only octet transfers can share a group, and a block size too small to use is
+/// left for the unicast transfer to refuse
+pub fn requested(request: &Request) -> bool {
+    let multicast = request
+        .options
+        .iter()
+        .any(|option| matches!(option, TftpOption::Multicast(_)));
+    multicast
+        && matches!(Mode::try_from(request.mode), Ok(Mode::Octet))
+        && requested_block_size(&request.options) >= Group::MIN_BLOCK_SIZE
+}
+
+/// The block size a client asked for, 512 if it didn't
+pub fn requested_block_size(options: &[TftpOption]) -> usize {
+    options
+        .iter()
+        .find_map(|option| match option {
+            TftpOption::BlockSize(block_size) => Some(*block_size),
+            _ => None,
+        })
+        .unwrap_or(Group::DEFAULT_BLOCK_SIZE)
+}
+
+/// One file being sent to a multicast group (RFC 2090). Every DATA packet goes to the whole
+/// group, but only the master client acknowledges them, one block at a time. A client that
+/// joins late picks up whatever blocks are sent from then on, and gets the ones it missed
+/// once it becomes master and acknowledges the last block it has in sequence. When the master
+/// leaves, the next client to join is made master in its place, and once every client has left
+/// the group is over.
+///
+/// Like [crate::machine::Machine] a [Group] has no sockets or clocks of its own, whoever drives
+/// it passes in packets with [Group::handle()], calls [Group::tick()] once [Group::deadline()]
+/// has passed and carries out every [Action]
+#[derive(Debug)]
+pub struct Group {
+    file: Box<dyn ReadFile>,
+    file_size: u64,
+    address: SocketAddrV4,
+    block_size: usize,
+    timeout: Duration,
+    retries: usize,
+    members: VecDeque<Member>,
+    /// The last block acknowledged by a master, counted from the start so it never wraps
+    block: u64,
+    /// Whether the current master has acknowledged anything yet. A new master can acknowledge
+    /// a block before [Group::block] if it joined late
+    master_acked: bool,
+    deadline: Option<Instant>,
+    retransmits: usize,
+    last_sent: Vec<(SocketAddr, Vec<u8>)>,
+}
+
+impl Group {
+    const DEFAULT_BLOCK_SIZE: usize = 512;
+    const MIN_BLOCK_SIZE: usize = 8;
+
+    /// A group for `file`, sent to `address` with the block size and timeout the first client
+    /// asked for in `options`. Clients join with [Group::join()], including the first
+    pub fn new(
+        file: Box<dyn ReadFile>,
+        file_size: u64,
+        address: SocketAddrV4,
+        options: &[TftpOption],
+        config: &Config,
+    ) -> Self {
+        let block_size = requested_block_size(options)
+            .max(Self::MIN_BLOCK_SIZE)
+            .min(config.max_block_size)
+            .min(Config::MAX_BLOCK_SIZE);
+        let timeout = options
+            .iter()
+            .find_map(|option| match option {
+                TftpOption::Timeout(timeout @ 1..=255) => {
+                    Some(Duration::from_secs(*timeout as u64))
+                }
+                _ => None,
+            })
+            .unwrap_or(config.timeout);
+
+        Self {
+            file,
+            file_size,
+            address,
+            block_size,
+            timeout,
+            retries: config.retries,
+            members: VecDeque::new(),
+            block: 0,
+            master_acked: false,
+            deadline: None,
+            retransmits: 0,
+            last_sent: Vec::new(),
+        }
+    }
+
+    /// Where every DATA packet goes
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    /// A client can only join if it can take blocks this big
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// When [Group::tick()] next needs to be called, if we are waiting on the master
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Every client has left, there is nothing left for this [Group] to do
+    pub fn is_finished(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Add a client that asked for the file with `options`. A client that is already in the
+    /// group is retransmitting its request and just gets its OACK again
+    pub fn join(
+        &mut self,
+        client: SocketAddr,
+        options: &[TftpOption],
+        now: Instant,
+    ) -> Vec<Action> {
+        if let Some(index) = self.members.iter().position(|member| member.addr == client) {
+            let oack = self.oack(&self.members[index], index == 0);
+            return vec![Action::Send(client, oack)];
+        }
+
+        let options = self.confirm(options);
+        self.members.push_back(Member {
+            addr: client,
+            options,
+            joined: now,
+        });
+        if self.members.len() > 1 {
+            let oack = self.oack(self.members.back().unwrap(), false);
+            return vec![
+                Action::Joined {
+                    client,
+                    master: false,
+                },
+                Action::Send(client, oack),
+            ];
+        }
+
+        let mut actions = vec![Action::Joined {
+            client,
+            master: true,
+        }];
+        actions.extend(self.promote(now));
+        actions
+    }
+
+    /// The master has gone, so the next client in line takes over if there is one
+    fn hand_over(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions: Vec<Action> = self
+            .members
+            .front()
+            .map(|next| Action::Master(next.addr))
+            .into_iter()
+            .collect();
+        actions.extend(self.promote(now));
+        actions
+    }
+
+    /// Work out what to do about a packet sent to the group's port
+    pub fn handle(&mut self, from: SocketAddr, data: &[u8], now: Instant) -> Vec<Action> {
+        let Some(index) = self.members.iter().position(|member| member.addr == from) else {
+            let error = ProtocolError::UnknownTransferId.into();
+            return vec![Action::Send(from, Tftp::serialise_error(&error))];
+        };
+
+        match Tftp::parse(data) {
+            Ok(Tftp::Acknowledgement(ack)) if index == 0 => self.acknowledged(ack.block, now),
+            // Only the master's ACKs count, the rest of the group keeps quiet until it is
+            // their turn
+            Ok(Tftp::Acknowledgement(_)) => Vec::new(),
+            Ok(Tftp::Error(error)) => {
+                let error = ProtocolError::Remote {
+                    code: error.code,
+                    message: error.message,
+                };
+                self.leave(index, error.into(), false, now)
+            }
+            Ok(packet) => {
+                let error = ProtocolError::UnexpectedPacket(packet.name()).into();
+                self.leave(index, error, true, now)
+            }
+            Err(error) => self.leave(index, error.into(), true, now),
+        }
+    }
+
+    /// If the master has not been heard from since the deadline we resend whatever we sent
+    /// last. A master that runs out of retries is dropped and the next client takes over
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {}
+            _ => return Vec::new(),
+        }
+
+        if self.retransmits >= self.retries {
+            let error = ProtocolError::TimedOut {
+                retries: self.retransmits,
+            };
+            return self.leave(0, error.into(), true, now);
+        }
+        self.retransmits += 1;
+        self.deadline = Some(now + self.timeout);
+
+        self.last_sent
+            .iter()
+            .cloned()
+            .map(|(to, packet)| Action::Send(to, packet))
+            .collect()
+    }
+
+    /// The master has every block up to the one it acknowledged, so the group gets the next
+    fn acknowledged(&mut self, wire_block: u16, now: Instant) -> Vec<Action> {
+        let block = self.unwrap_block(wire_block);
+        let final_block = self.file_size / self.block_size as u64 + 1;
+
+        if block >= final_block {
+            let master = self.members.pop_front().unwrap();
+            let mut actions = vec![Action::Completed {
+                client: master.addr,
+                bytes: self.file_size,
+                duration: now.saturating_duration_since(master.joined),
+            }];
+            actions.extend(self.hand_over(now));
+            return actions;
+        }
+        // A repeat of an ACK we have already sent the next block for, resending it again
+        // would double up every packet from here on
+        if self.master_acked && block <= self.block {
+            return Vec::new();
+        }
+
+        self.block = block;
+        self.master_acked = true;
+        match self.data(block + 1) {
+            Ok(packet) => self.send(vec![(SocketAddr::V4(self.address), packet)], now),
+            Err(error) => self.fail_all(error),
+        }
+    }
+
+    /// Drop the client at `index`, telling it why unless it told us. If it was the master the
+    /// next client takes over
+    fn leave(&mut self, index: usize, error: Error, notify: bool, now: Instant) -> Vec<Action> {
+        let member = self.members.remove(index).unwrap();
+        let mut actions = Vec::new();
+        if notify {
+            actions.push(Action::Send(member.addr, Tftp::serialise_error(&error)));
+        }
+        actions.push(Action::Failed(member.addr, error));
+        if index == 0 {
+            actions.extend(self.hand_over(now));
+        }
+        actions
+    }
+
+    /// Storage failed us, so nobody is getting the file
+    fn fail_all(&mut self, error: io::Error) -> Vec<Action> {
+        let packet = Tftp::serialise_error(&Error::Io(io::Error::new(error.kind(), "")));
+        let mut actions = Vec::new();
+        for member in self.members.drain(..) {
+            actions.push(Action::Send(member.addr, packet.clone()));
+            let error = io::Error::new(error.kind(), error.to_string());
+            actions.push(Action::Failed(member.addr, error.into()));
+        }
+        self.deadline = None;
+        actions
+    }
+
+    /// Tell the client at the front it is now master. It answers with an ACK of the last block
+    /// it has in sequence, and we carry on from there
+    fn promote(&mut self, now: Instant) -> Vec<Action> {
+        let Some(master) = self.members.front() else {
+            self.deadline = None;
+            self.last_sent.clear();
+            return Vec::new();
+        };
+        let addr = master.addr;
+        let oack = self.oack(master, true);
+
+        self.master_acked = false;
+        self.send(vec![(addr, oack)], now)
+    }
+
+    fn send(&mut self, packets: Vec<(SocketAddr, Vec<u8>)>, now: Instant) -> Vec<Action> {
+        self.last_sent = packets;
+        self.retransmits = 0;
+        self.deadline = Some(now + self.timeout);
+        self.last_sent
+            .iter()
+            .cloned()
+            .map(|(to, packet)| Action::Send(to, packet))
+            .collect()
+    }
+
+    /// Confirm what the group can offer a new client, the block size and timeout are fixed by
+    /// the client that started it. RFC 2349 only lets us confirm a timeout as it was asked for
+    fn confirm(&self, options: &[TftpOption]) -> Vec<TftpOption> {
+        options
+            .iter()
+            .filter_map(|option| match option {
+                TftpOption::BlockSize(_) => Some(TftpOption::BlockSize(self.block_size)),
+                TftpOption::TransferSize(_) => {
+                    Some(TftpOption::TransferSize(self.file_size as usize))
+                }
+                TftpOption::Timeout(timeout)
+                    if Duration::from_secs(*timeout as u64) == self.timeout =>
+                {
+                    Some(TftpOption::Timeout(*timeout))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn oack(&self, member: &Member, master: bool) -> Vec<u8> {
+        let mut options = member.options.clone();
+        options.push(TftpOption::Multicast(format!(
+            "{},{},{}",
+            self.address.ip(),
+            self.address.port(),
+            u8::from(master)
+        )));
+        Tftp::OptionAcknowledgement(OptionAcknowledgement { options }).serialise()
+    }
+
+    fn data(&self, block: u64) -> io::Result<Vec<u8>> {
+        let offset = (block - 1) * self.block_size as u64;
+        let length = (self.file_size - offset).min(self.block_size as u64) as usize;
+        let mut data = vec![0; length];
+        self.file.read_exact_at(&mut data, offset)?;
+
+        let data = Data {
+            block: (block % 65536) as u16,
+            data: &data,
+        };
+        Ok(Tftp::Data(data).serialise())
+    }
+
+    /// Work out which block an ACK means. A master that has been acknowledging is somewhere
+    /// near the last block it did, but a new one could be anywhere so we assume the earliest
+    /// block it could mean and at worst resend some it already has
+    fn unwrap_block(&self, wire_block: u16) -> u64 {
+        let wire_block = wire_block as u64;
+        if !self.master_acked {
+            return wire_block;
+        }
+
+        let near = (self.block & !0xffff) | wire_block;
+        [near.checked_sub(65536), Some(near), Some(near + 65536)]
+            .into_iter()
+            .flatten()
+            .min_by_key(|block| block.abs_diff(self.block))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::{Acknowledgement, ErrorCode, ErrorMessage};
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    const GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 1), 1758);
+
+    fn client(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    /// A group sending 1200 bytes in 512 byte blocks, so the final block is 3
+    fn group() -> Group {
+        let file: Arc<[u8]> = vec![7; 1200].into();
+        let options = [TftpOption::Multicast(String::new())];
+        Group::new(Box::new(file), 1200, GROUP, &options, &Config::default())
+    }
+
+    fn ack(group: &mut Group, from: SocketAddr, block: u16, now: Instant) -> Vec<Action> {
+        let ack = Tftp::Acknowledgement(Acknowledgement { block }).serialise();
+        group.handle(from, &ack, now)
+    }
+
+    /// The client an OACK is sent to and whether it makes that client master
+    fn oack(action: &Action) -> (SocketAddr, bool) {
+        let Action::Send(to, packet) = action else {
+            panic!("expected an OACK, got {action:?}");
+        };
+        let Ok(Tftp::OptionAcknowledgement(oack)) = Tftp::parse(packet) else {
+            panic!("expected an OACK, got {packet:?}");
+        };
+        let master = oack.options.iter().find_map(|option| match option {
+            TftpOption::Multicast(value) => Some(value.ends_with(",1")),
+            _ => None,
+        });
+        (*to, master.unwrap())
+    }
+
+    /// The block number of a DATA packet sent to the whole group
+    fn data(action: &Action) -> u16 {
+        match action {
+            Action::Send(SocketAddr::V4(GROUP), packet) => match Tftp::parse(packet) {
+                Ok(Tftp::Data(data)) => data.block,
+                packet => panic!("expected DATA, got {packet:?}"),
+            },
+            action => panic!("expected DATA for the group, got {action:?}"),
+        }
+    }
+
+    #[test]
+    fn promotes_the_next_client_once_the_master_completes() {
+        let now = Instant::now();
+        let (first, second) = (client(1001), client(1002));
+        let mut group = group();
+
+        let actions = group.join(first, &[], now);
+        assert!(matches!(actions[0], Action::Joined { master: true, .. }));
+        assert_eq!(oack(&actions[1]), (first, true));
+        let actions = group.join(second, &[], now);
+        assert!(matches!(actions[0], Action::Joined { master: false, .. }));
+        assert_eq!(oack(&actions[1]), (second, false));
+
+        // The second client's ACKs are ignored until it is master
+        assert!(ack(&mut group, second, 0, now).is_empty());
+        assert_eq!(data(&ack(&mut group, first, 0, now)[0]), 1);
+        assert_eq!(data(&ack(&mut group, first, 1, now)[0]), 2);
+        assert_eq!(data(&ack(&mut group, first, 2, now)[0]), 3);
+
+        let actions = ack(&mut group, first, 3, now);
+        assert!(
+            matches!(actions[0], Action::Completed { client, bytes: 1200, .. } if client == first)
+        );
+        assert!(matches!(actions[1], Action::Master(client) if client == second));
+        assert_eq!(oack(&actions[2]), (second, true));
+
+        // It joined late, so it picks up from the last block it has in sequence
+        assert_eq!(data(&ack(&mut group, second, 0, now)[0]), 1);
+        ack(&mut group, second, 3, now);
+        assert!(group.is_finished());
+    }
+
+    #[test]
+    fn carries_on_when_a_client_leaves() {
+        let now = Instant::now();
+        let (first, second, third) = (client(1001), client(1002), client(1003));
+        let mut group = group();
+        group.join(first, &[], now);
+        group.join(second, &[], now);
+        group.join(third, &[], now);
+        ack(&mut group, first, 0, now);
+
+        // A client that isn't master leaving changes nothing for the rest
+        let error = Tftp::Error(ErrorMessage {
+            code: ErrorCode::NotDefined,
+            message: "Cancelled".to_owned(),
+        })
+        .serialise();
+        let actions = group.handle(second, &error, now);
+        assert!(matches!(actions[..], [Action::Failed(client, _)] if client == second));
+        assert_eq!(data(&ack(&mut group, first, 1, now)[0]), 2);
+
+        // When the master leaves the next one still in the group takes over
+        let actions = group.handle(first, &error, now);
+        assert!(matches!(actions[0], Action::Failed(client, _) if client == first));
+        assert!(matches!(actions[1], Action::Master(client) if client == third));
+        assert_eq!(oack(&actions[2]), (third, true));
+    }
+
+    #[test]
+    fn drops_a_master_that_stops_answering() {
+        let mut now = Instant::now();
+        let (first, second) = (client(1001), client(1002));
+        let mut group = group();
+        group.join(first, &[], now);
+        group.join(second, &[], now);
+        ack(&mut group, first, 0, now);
+
+        for _ in 0..Config::default().retries {
+            now += Duration::from_secs(60);
+            assert_eq!(data(&group.tick(now)[0]), 1);
+        }
+        now += Duration::from_secs(60);
+        let actions = group.tick(now);
+        assert!(matches!(&actions[0], Action::Send(client, _) if *client == first));
+        assert!(matches!(actions[1], Action::Failed(client, _) if client == first));
+        assert!(matches!(actions[2], Action::Master(client) if client == second));
+    }
+}
@@ -0,0 +1,204 @@
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0x00;
+
+/// The number of bytes `data` takes up once translated to netascii
+pub fn encoded_len(data: &[u8]) -> usize {
+    data.len() + data.iter().filter(|byte| matches!(**byte, CR | LF)).count()
+}
+
+/// Translate to netascii, every LF becomes CR LF and every CR becomes CR NUL
+pub fn encode(data: &[u8], encoded: &mut Vec<u8>) {
+    encoded.reserve(encoded_len(data));
+    for byte in data {
+        match *byte {
+            LF => encoded.extend_from_slice(&[CR, LF]),
+            CR => encoded.extend_from_slice(&[CR, NUL]),
+            byte => encoded.push(byte),
+        }
+    }
+}
+
+/// Translates a file being read to netascii a window at a time. Each window has to start where
+/// the last one did or later, so we only ever hold on to the bytes from the start of the last
+/// window on
+#[derive(Debug)]
+pub struct Encoder {
+    /// Translated bytes, starting at `start` in the translated file
+    buffer: Vec<u8>,
+    start: u64,
+    /// Where the next read from the untranslated file starts
+    offset: u64,
+    size: u64,
+}
+
+impl Encoder {
+    /// An encoder for an untranslated file of `size` bytes
+    pub fn new(size: u64) -> Self {
+        Self {
+            buffer: Vec::new(),
+            start: 0,
+            offset: 0,
+            size,
+        }
+    }
+
+    /// Get ready for the translated bytes from `start` to `end`, returning the offset and
+    /// length of the untranslated bytes still to be read to fill them. Every byte translates
+    /// to at least one so we never need more than one read
+    pub fn prepare(&mut self, start: u64, end: u64) -> Option<(u64, usize)> {
+        let drop = (start.saturating_sub(self.start) as usize).min(self.buffer.len());
+        self.buffer.drain(..drop);
+        self.start += drop as u64;
+
+        let buffered = self.start + self.buffer.len() as u64;
+        let length = end.saturating_sub(buffered).min(self.size - self.offset) as usize;
+        (length > 0).then_some((self.offset, length))
+    }
+
+    /// Translate untranslated bytes read from where [Encoder::prepare()] asked
+    pub fn push(&mut self, data: &[u8]) {
+        self.offset += data.len() as u64;
+        encode(data, &mut self.buffer);
+    }
+
+    /// The translated bytes from `start` to `end`, or up to the end of the file if it is sooner
+    pub fn window(&self, start: u64, end: u64) -> Vec<u8> {
+        let from = (start.saturating_sub(self.start) as usize).min(self.buffer.len());
+        let to = (end.saturating_sub(self.start) as usize).min(self.buffer.len());
+        self.buffer[from..to].to_vec()
+    }
+}
+
+/// Translates netascii back as it arrives, a CR at the end of one block is held on to until we
+/// see what follows it in the next
+#[derive(Debug, Default)]
+pub struct Decoder {
+    cr: bool,
+}
+
+impl Decoder {
+    /// CR LF becomes LF and CR NUL becomes CR, a CR followed by anything else is kept as is
+    pub fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::with_capacity(data.len() + 1);
+        for byte in data {
+            match (self.cr, *byte) {
+                (true, LF) => decoded.push(LF),
+                (true, NUL) | (true, CR) => decoded.push(CR),
+                (true, byte) => decoded.extend_from_slice(&[CR, byte]),
+                (false, CR) => {}
+                (false, byte) => decoded.push(byte),
+            }
+            self.cr = *byte == CR;
+        }
+        decoded
+    }
+
+    /// Whatever is still held on to at the end of the transfer
+    pub fn finish(&mut self) -> Vec<u8> {
+        if std::mem::take(&mut self.cr) {
+            vec![CR]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"one\ntwo\r\nthree\rfour\n\n\r\r";
+
+    fn encoded(data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encode(data, &mut encoded);
+        encoded
+    }
+
+    /// Read `data` through an [Encoder] `window` translated bytes at a time
+    fn windows(data: &[u8], window: u64) -> Vec<Vec<u8>> {
+        let mut encoder = Encoder::new(data.len() as u64);
+        let mut windows = Vec::new();
+        for start in (0..).step_by(window as usize) {
+            if let Some((offset, length)) = encoder.prepare(start, start + window) {
+                let offset = offset as usize;
+                encoder.push(&data[offset..offset + length]);
+            }
+            let window = encoder.window(start, start + window);
+            if window.is_empty() {
+                return windows;
+            }
+            windows.push(window);
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn encoded_len_agrees_with_encode() {
+        for data in [&b""[..], b"plain", b"\r", b"\n", b"\r\n", b"\n\r", TEXT] {
+            assert_eq!(encoded_len(data), encoded(data).len(), "{data:?}");
+        }
+        assert_eq!(
+            encoded(TEXT),
+            b"one\r\ntwo\r\0\r\nthree\r\0four\r\n\r\n\r\0\r\0"
+        );
+    }
+
+    #[test]
+    fn splits_a_translated_line_ending_across_windows() {
+        // The CR of each pair ends the first window and what follows it starts the next
+        assert_eq!(windows(b"ab\ncd", 3), [&b"ab\r"[..], b"\ncd"]);
+        assert_eq!(windows(b"ab\rcd", 3), [&b"ab\r"[..], b"\0cd"]);
+        assert_eq!(windows(b"ab\r", 3), [&b"ab\r"[..], b"\0"]);
+    }
+
+    #[test]
+    fn encodes_the_same_whatever_the_window() {
+        for window in 1..=(encoded_len(TEXT) as u64 + 1) {
+            assert_eq!(windows(TEXT, window).concat(), encoded(TEXT), "{window}");
+        }
+    }
+
+    #[test]
+    fn can_go_back_to_the_start_of_the_last_window() {
+        let mut encoder = Encoder::new(TEXT.len() as u64);
+        let (offset, length) = encoder.prepare(0, 8).unwrap();
+        encoder.push(&TEXT[offset as usize..][..length]);
+        assert_eq!(encoder.window(4, 8), b"\ntwo");
+
+        // A resend from the middle of the window needs nothing more read
+        assert_eq!(encoder.prepare(4, 8), None);
+        assert_eq!(encoder.window(4, 8), b"\ntwo");
+        // and going further only reads what the buffer is short of
+        assert_eq!(encoder.prepare(4, 12), Some((8, 2)));
+        encoder.push(&TEXT[8..10]);
+        assert_eq!(encoder.window(4, 12), b"\ntwo\r\0\r\n");
+    }
+
+    #[test]
+    fn decodes_a_line_ending_split_across_blocks() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(b"one\r"), b"one");
+        assert_eq!(decoder.decode(b"\ntwo\r"), b"\ntwo");
+        assert_eq!(decoder.decode(b"\0three\r"), b"\rthree");
+        assert_eq!(decoder.decode(b"x"), b"\rx");
+        assert_eq!(decoder.decode(b"\r"), b"");
+        assert_eq!(decoder.finish(), b"\r");
+        assert_eq!(decoder.finish(), b"");
+    }
+
+    #[test]
+    fn decodes_what_it_encoded_whatever_the_blocks() {
+        let encoded = encoded(TEXT);
+        for block in 1..=encoded.len() {
+            let mut decoder = Decoder::default();
+            let mut decoded: Vec<u8> = encoded
+                .chunks(block)
+                .flat_map(|chunk| decoder.decode(chunk))
+                .collect();
+            decoded.extend(decoder.finish());
+            assert_eq!(decoded, TEXT, "{block}");
+        }
+    }
+}
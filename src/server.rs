@@ -0,0 +1,886 @@
+use crate::{
+    error::{Error, ProtocolError},
+    log::{Level, Logger, Value},
+    machine::{Config, Direction, Event, Output},
+    metrics::Metrics,
+    multicast::{self, Action, Group},
+    session::Session,
+    storage::{FileProvider, LocalFs},
+    tftp::{ErrorCode, Request, Tftp, TftpOption, UDP_BUFFER_SIZE},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, ToSocketAddrs, UdpSocket},
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SendError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a worker waits to hear from its client when there is no retransmit deadline
+const TICK: Duration = Duration::from_millis(100);
+
+/// Listens for requests and hands each one to a worker thread with its own socket
+pub struct Server {
+    listeners: Vec<UdpSocket>,
+    config: Config,
+    storage: Arc<dyn FileProvider>,
+    transfer_ports: Option<RangeInclusive<u16>>,
+    logger: Logger,
+    metrics: Arc<Metrics>,
+    /// Where [Metrics] are served over HTTP, if anywhere
+    metrics_listener: Option<TcpListener>,
+    sessions: Arc<TftpSessions>,
+    /// Transfers allowed at once across every client
+    max_sessions: usize,
+    /// Transfers allowed at once from a single IP address
+    max_client_sessions: usize,
+    /// The address of the first multicast group, if multicast is on
+    multicast: Option<SocketAddrV4>,
+    groups: Arc<Groups>,
+}
+
+/// A [Session] along with the socket it was given, whose port is the server's TID for the
+/// transfer. Each one is run by its own worker thread
+struct Transfer {
+    socket: UdpSocket,
+    session: Session,
+    logger: Logger,
+    metrics: Arc<Metrics>,
+}
+
+/// A multicast [Group] along with the socket it was given, run by its own worker thread.
+/// Clients that join after it has started are handed over through `joins`
+struct Multicast {
+    socket: UdpSocket,
+    group: Group,
+    filename: String,
+    joins: Receiver<Join>,
+    groups: Arc<Groups>,
+    /// The place each client in the group holds under the session caps
+    slots: HashMap<SocketAddr, Slot>,
+    logger: Logger,
+    metrics: Arc<Metrics>,
+}
+
+/// Every multicast group running, by the filename it is sending
+type Groups = Mutex<HashMap<String, GroupHandle>>;
+
+/// How the listener finds and joins a running [Multicast]
+struct GroupHandle {
+    address: SocketAddrV4,
+    block_size: usize,
+    joins: Sender<Join>,
+}
+
+impl GroupHandle {
+    /// Send a client to the group, handing its [Slot] back if the group wants bigger blocks
+    /// than the client asked for or has already finished
+    fn join(&self, slot: Slot, options: Vec<TftpOption>) -> Result<(), Slot> {
+        if multicast::requested_block_size(&options) < self.block_size {
+            return Err(slot);
+        }
+        let join = Join {
+            client: slot.client,
+            options,
+            slot: Some(slot),
+        };
+        self.joins
+            .send(join)
+            .map_err(|SendError(join)| join.slot.unwrap())
+    }
+}
+
+/// A client asking to join a [Group], with the options from its request and the place it was
+/// given under the session caps. A client already in the group has no new place to give
+struct Join {
+    client: SocketAddr,
+    options: Vec<TftpOption>,
+    slot: Option<Slot>,
+}
+
+/// A transfer's place under the session caps, given back when it is dropped so a worker that
+/// panics still frees it. Unicast workers and multicast members each hold one
+struct Slot {
+    sessions: Arc<TftpSessions>,
+    client: SocketAddr,
+    metrics: Arc<Metrics>,
+}
+
+/// Every client with a transfer in progress, grouped by IP address so we can count how many
+/// each has. The table is split into shards so workers starting and finishing for different
+/// clients rarely wait on the same lock, and a lock is never held for longer than an insert or
+/// remove
+struct TftpSessions {
+    shards: Vec<Mutex<HashMap<IpAddr, HashSet<SocketAddr>>>>,
+    active: AtomicUsize,
+}
+
+/// Why [TftpSessions::insert()] turned a client away
+enum Refused {
+    /// The client already has this transfer, it is retransmitting its request
+    Duplicate,
+    /// Taking on the transfer would go over one of the caps
+    Full,
+}
+
+impl Server {
+    /// Every transfer has its own thread and socket, so there has to be a limit somewhere
+    const DEFAULT_MAX_SESSIONS: usize = 1024;
+    const DEFAULT_MAX_CLIENT_SESSIONS: usize = 64;
+    /// How many multicast groups can run at once, each needs an address of its own
+    const MAX_GROUPS: u32 = 256;
+
+    /// Bind a listening socket on every address in `addrs`, every [Session] starts out with
+    /// `config` and serves files from the current directory
+    pub fn bind(addrs: impl ToSocketAddrs, config: Config) -> io::Result<Self> {
+        let listeners = addrs
+            .to_socket_addrs()?
+            .map(UdpSocket::bind)
+            .collect::<io::Result<Vec<_>>>()?;
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No address to listen on",
+            ));
+        }
+
+        Ok(Self {
+            listeners,
+            config,
+            storage: Arc::new(LocalFs::new(".")),
+            transfer_ports: None,
+            logger: Logger::default(),
+            metrics: Arc::new(Metrics::default()),
+            metrics_listener: None,
+            sessions: Arc::new(TftpSessions::new()),
+            max_sessions: Self::DEFAULT_MAX_SESSIONS,
+            max_client_sessions: Self::DEFAULT_MAX_CLIENT_SESSIONS,
+            multicast: None,
+            groups: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Serve files from and upload files to `root` on the local filesystem instead of the
+    /// current directory
+    pub fn root(self, root: impl Into<PathBuf>) -> Self {
+        self.storage(LocalFs::new(root))
+    }
+
+    /// Serve files from and upload files to `storage` instead of the local filesystem
+    pub fn storage(mut self, storage: impl FileProvider + 'static) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    /// Only give transfers a TID from `ports`, e.g. to fit through a firewall. By default the
+    /// OS picks any ephemeral port
+    pub fn transfer_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.transfer_ports = Some(ports);
+        self
+    }
+
+    /// Turn requests away once this many transfers are in progress, 1024 by default
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    /// Turn requests from an IP address away once it has this many transfers in progress, 64
+    /// by default. A client can have as many as it likes on ports we have no transfer with
+    pub fn max_client_sessions(mut self, max_client_sessions: usize) -> Self {
+        self.max_client_sessions = max_client_sessions;
+        self
+    }
+
+    /// How much to log and what it looks like, by default warnings and errors as text
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// Send files to clients that ask for multicast (RFC 2090) through a group per file, so a
+    /// file many clients want at once is only read and sent once. Groups take successive
+    /// addresses from `first`, all on its port. Without this every client gets its own unicast
+    /// transfer
+    pub fn multicast(mut self, first: SocketAddrV4) -> Self {
+        self.multicast = Some(first);
+        self
+    }
+
+    /// Serve [Metrics] to Prometheus at `/metrics` on `listener`. They are counted either way
+    pub fn metrics_listener(mut self, listener: TcpListener) -> Self {
+        self.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Everything the server has counted so far
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Serve requests forever, with a thread listening on each address and another for
+    /// metrics if they have somewhere to go
+    pub fn run(&self) -> Result<(), Error> {
+        thread::scope(|scope| {
+            for listener in &self.listeners {
+                scope.spawn(|| self.listen(listener));
+            }
+            if let Some(listener) = &self.metrics_listener {
+                scope.spawn(|| self.metrics.serve(listener));
+            }
+        });
+        Ok(())
+    }
+
+    fn listen(&self, listener: &UdpSocket) {
+        let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+
+        loop {
+            match listener.recv_from(&mut buffer) {
+                Ok((len, client)) => {
+                    self.metrics.received(&buffer[..len]);
+                    self.accept(listener, client, &buffer[..len]);
+                }
+                // A failed receive (e.g. an ICMP port unreachable from a client that went away)
+                // should never take down the server
+                Err(error) => self.logger.log(
+                    Level::Error,
+                    "Failed to receive",
+                    &[("error", Value::Text(&error))],
+                ),
+            }
+        }
+    }
+
+    /// The listening port only ever sees the first packet of a transfer. Each new client gets
+    /// a brand new [Session] and its own socket on a fresh port, handed to a worker thread that
+    /// sees the transfer through to the end so the listener is straight back to accepting
+    /// requests.
+    ///
+    /// A client we already have a [Session] with is retransmitting its request because our
+    /// reply went missing, the [Session] will resend it on its own so there is nothing to do.
+    /// Once we have as many transfers as we allow the client is told to try again later.
+    fn accept(&self, listener: &UdpSocket, client: SocketAddr, data: &[u8]) {
+        match self
+            .sessions
+            .insert(client, self.max_sessions, self.max_client_sessions)
+        {
+            Ok(()) => {}
+            Err(Refused::Duplicate) => {
+                self.rejoin(client, data);
+                return;
+            }
+            Err(Refused::Full) => {
+                let error = ProtocolError::TooManyTransfers.into();
+                self.logger.log(
+                    Level::Warn,
+                    "Refused request",
+                    &[
+                        ("client", Value::Text(&client)),
+                        ("error", Value::Text(&error)),
+                    ],
+                );
+                self.metrics.failed(ErrorCode::from(&error) as u16);
+                _ = listener.send_to(&Tftp::serialise_error(&error), client);
+                return;
+            }
+        }
+        let mut slot = Slot::new(self.sessions.clone(), client, self.metrics.clone());
+
+        if let Ok(Tftp::ReadRequest(request)) = Tftp::parse(data) {
+            if self.multicast.is_some() && multicast::requested(&request) {
+                match self.join_group(listener, slot, &request) {
+                    Ok(()) => return,
+                    Err(refused) => slot = refused,
+                }
+            }
+        }
+
+        let socket = match bind_transfer_socket(listener, self.transfer_ports.clone()) {
+            Ok(socket) => socket,
+            Err(error) => {
+                let error = error.into();
+                self.logger.log(
+                    Level::Error,
+                    "Failed to start transfer",
+                    &[
+                        ("client", Value::Text(&client)),
+                        ("error", Value::Text(&error)),
+                    ],
+                );
+                self.metrics.failed(ErrorCode::from(&error) as u16);
+                _ = listener.send_to(&Tftp::serialise_error(&error), client);
+                return;
+            }
+        };
+        let mut transfer = Transfer {
+            socket,
+            session: Session::new(&self.config, self.storage.clone()),
+            logger: self.logger,
+            metrics: self.metrics.clone(),
+        };
+        let request = data.to_vec();
+
+        // If the thread can't be spawned the slot is dropped along with the closure
+        let worker = thread::Builder::new()
+            .name(format!("tftp {client}"))
+            .spawn(move || {
+                let _slot = slot;
+                transfer.run(client, &request);
+            });
+        if let Err(error) = worker {
+            self.logger.log(
+                Level::Error,
+                "Failed to start transfer",
+                &[
+                    ("client", Value::Text(&client)),
+                    ("error", Value::Text(&error)),
+                ],
+            );
+        }
+    }
+
+    /// Pass a multicast request on to the client's group, in case it is resending it because
+    /// its OACK went missing
+    fn rejoin(&self, client: SocketAddr, data: &[u8]) {
+        let Ok(Tftp::ReadRequest(request)) = Tftp::parse(data) else {
+            return;
+        };
+        if self.multicast.is_none() || !multicast::requested(&request) {
+            return;
+        }
+        if let Some(handle) = self.groups.lock().unwrap().get(request.filename) {
+            _ = handle.joins.send(Join {
+                client,
+                options: request.options.clone(),
+                slot: None,
+            });
+        }
+    }
+
+    /// Add a client to the group sending the file it asked for, starting one if there isn't
+    /// one yet. The client's [Slot] is handed back if it can't join, so it gets a unicast
+    /// transfer instead. That includes when the file can't be opened, as the unicast transfer
+    /// tells the client why
+    fn join_group(&self, listener: &UdpSocket, slot: Slot, request: &Request) -> Result<(), Slot> {
+        let Some(first) = self.multicast else {
+            return Err(slot);
+        };
+        let options = request.options.clone();
+        if let Some(handle) = self.groups.lock().unwrap().get(request.filename) {
+            return handle.join(slot, options);
+        }
+
+        // Opening the file can be slow, so it is done without holding up every other request
+        // that wants a group
+        let Ok((file, size)) = self.storage.open_read(request.filename) else {
+            return Err(slot);
+        };
+        let mut groups = self.groups.lock().unwrap();
+        // Someone else may have started a group for the file while it was opening
+        if let Some(handle) = groups.get(request.filename) {
+            return handle.join(slot, options);
+        }
+
+        let Some(address) = (0..Self::MAX_GROUPS)
+            .map(|offset| {
+                let ip = Ipv4Addr::from(u32::from(*first.ip()).wrapping_add(offset));
+                SocketAddrV4::new(ip, first.port())
+            })
+            .find(|address| groups.values().all(|handle| handle.address != *address))
+        else {
+            return Err(slot);
+        };
+        let Ok(socket) = bind_transfer_socket(listener, self.transfer_ports.clone()) else {
+            return Err(slot);
+        };
+
+        let group = Group::new(file, size, address, &options, &self.config);
+        let block_size = group.block_size();
+        let (joins, incoming) = mpsc::channel();
+        let mut multicast = Multicast {
+            socket,
+            group,
+            filename: request.filename.to_owned(),
+            joins: incoming,
+            groups: self.groups.clone(),
+            slots: HashMap::new(),
+            logger: self.logger,
+            metrics: self.metrics.clone(),
+        };
+
+        // The join is only sent once the worker is running, so we still have the slot to give
+        // back if it isn't
+        let worker = thread::Builder::new()
+            .name(format!("tftp multicast {address}"))
+            .spawn(move || multicast.run());
+        if worker.is_err() {
+            return Err(slot);
+        }
+        _ = joins.send(Join {
+            client: slot.client,
+            options,
+            slot: Some(slot),
+        });
+        groups.insert(
+            request.filename.to_owned(),
+            GroupHandle {
+                address,
+                block_size,
+                joins,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Bind a socket for a new [Transfer] on the same address as the listener it came in on,
+/// within [Server::transfer_ports()] if set
+fn bind_transfer_socket(
+    listener: &UdpSocket,
+    ports: Option<RangeInclusive<u16>>,
+) -> io::Result<UdpSocket> {
+    let ip: IpAddr = listener.local_addr()?.ip();
+
+    match ports {
+        None => UdpSocket::bind((ip, 0)),
+        Some(mut ports) => ports
+            .find_map(|port| UdpSocket::bind((ip, port)).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "No free transfer port")),
+    }
+}
+
+impl Transfer {
+    /// Handle the client's request and then every packet that arrives on our socket until the
+    /// transfer is finished. Anything from the client is passed to [Session::handle()] and we
+    /// send whatever it gives us back, logging the [Error] if the transfer failed. Packets
+    /// from anyone else are told they have the wrong transfer ID without disturbing the
+    /// [Session]. Whenever the [Session] deadline passes we give it the chance to retransmit.
+    fn run(&mut self, client: SocketAddr, request: &[u8]) {
+        let outputs = self.session.handle(request, Instant::now());
+        self.send(&client, outputs);
+
+        let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+        while !self.session.is_finished() {
+            let timeout = self
+                .session
+                .deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(TICK)
+                .max(Duration::from_millis(1));
+            if let Err(error) = self.socket.set_read_timeout(Some(timeout)) {
+                self.logger.log(
+                    Level::Error,
+                    "Failed to set timeout",
+                    &[
+                        ("client", Value::Text(&client)),
+                        ("error", Value::Text(&error)),
+                    ],
+                );
+                return;
+            }
+
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, peer)) if peer == client => {
+                    self.metrics.received(&buffer[..len]);
+                    let outputs = self.session.handle(&buffer[..len], Instant::now());
+                    self.send(&client, outputs);
+                }
+                Ok((_, peer)) => {
+                    let error = ProtocolError::UnknownTransferId.into();
+                    self.logger.log(
+                        Level::Warn,
+                        "Packet from a stranger",
+                        &[
+                            ("client", Value::Text(&client)),
+                            ("peer", Value::Text(&peer)),
+                            ("error", Value::Text(&error)),
+                        ],
+                    );
+                    _ = self.socket.send_to(&Tftp::serialise_error(&error), peer);
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => self.logger.log(
+                    Level::Error,
+                    "Failed to receive",
+                    &[
+                        ("client", Value::Text(&client)),
+                        ("error", Value::Text(&error)),
+                    ],
+                ),
+            }
+
+            let outputs = self.session.tick(Instant::now());
+            self.send(&client, outputs);
+        }
+    }
+
+    /// Send the client everything we have for them, and log how the transfer is going
+    fn send(&mut self, client: &SocketAddr, outputs: Vec<Output>) {
+        for output in outputs {
+            match output {
+                Output::Send(packet) => match self.socket.send_to(&packet, client) {
+                    Ok(len) => self.metrics.sent(len),
+                    Err(error) => {
+                        self.logger.log(
+                            Level::Error,
+                            "Failed to send",
+                            &[
+                                ("client", Value::Text(client)),
+                                ("error", Value::Text(&error)),
+                            ],
+                        );
+                    }
+                },
+                Output::Failed(error) => {
+                    self.metrics.failed(ErrorCode::from(&error) as u16);
+                    self.logger.log(
+                        Level::Error,
+                        "Transfer failed",
+                        &[
+                            ("client", Value::Text(client)),
+                            ("code", Value::Number(ErrorCode::from(&error) as u64)),
+                            ("error", Value::Text(&error)),
+                        ],
+                    );
+                }
+                Output::Event(event) => self.event(client, &event),
+                // We ask the session for its deadline each time round instead
+                Output::Deadline(_) | Output::Storage(_) => {}
+            }
+        }
+    }
+
+    fn count(&self, event: &Event) {
+        match event {
+            Event::Negotiated {
+                block_size,
+                window_size,
+                ..
+            } => self.metrics.negotiated(*block_size, *window_size),
+            Event::Retransmit { .. } => self.metrics.retransmit(),
+            Event::Completed {
+                direction,
+                duration,
+                ..
+            } => self.metrics.completed(*direction, *duration),
+            Event::Request { .. } => {}
+        }
+    }
+
+    fn event(&self, client: &SocketAddr, event: &Event) {
+        self.count(event);
+        match event {
+            Event::Request {
+                direction,
+                filename,
+                mode,
+                options,
+            } => {
+                let options = options
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                self.logger.log(
+                    Level::Info,
+                    "Request",
+                    &[
+                        ("client", Value::Text(client)),
+                        ("direction", Value::Text(direction)),
+                        ("filename", Value::Text(filename)),
+                        ("mode", Value::Text(mode)),
+                        ("options", Value::Text(&options)),
+                    ],
+                );
+            }
+            Event::Negotiated {
+                block_size,
+                window_size,
+                timeout,
+                transfer_size,
+                rollover,
+            } => {
+                let mut fields = vec![
+                    ("client", Value::Text(client)),
+                    ("blksize", Value::from(*block_size)),
+                    ("windowsize", Value::from(*window_size)),
+                    ("timeout_ms", Value::Number(timeout.as_millis() as u64)),
+                    ("rollover", Value::Number(*rollover as u64)),
+                ];
+                if let Some(transfer_size) = transfer_size {
+                    fields.push(("tsize", Value::from(*transfer_size)));
+                }
+                self.logger.log(Level::Info, "Negotiated", &fields);
+            }
+            Event::Retransmit { block, attempt } => self.logger.log(
+                Level::Debug,
+                "Retransmit",
+                &[
+                    ("client", Value::Text(client)),
+                    ("block", Value::from(*block)),
+                    ("attempt", Value::from(*attempt)),
+                ],
+            ),
+            Event::Completed {
+                direction,
+                bytes,
+                duration,
+            } => {
+                // Anything quicker than a millisecond is counted as one
+                let throughput = *bytes as f64 / duration.as_secs_f64().max(0.001);
+                self.logger.log(
+                    Level::Info,
+                    "Completed",
+                    &[
+                        ("client", Value::Text(client)),
+                        ("direction", Value::Text(direction)),
+                        ("bytes", Value::from(*bytes)),
+                        ("duration_ms", Value::Number(duration.as_millis() as u64)),
+                        ("bytes_per_sec", Value::Number(throughput as u64)),
+                    ],
+                );
+            }
+        }
+    }
+}
+
+impl Multicast {
+    /// Take on every client that joins and handle every packet that arrives on our socket
+    /// until every client has left. The group is only taken out of [Server] once it is
+    /// finished with nobody waiting to join, checked under the lock joins are sent under, so a
+    /// client can never join a group that has already gone
+    fn run(&mut self) {
+        let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+
+        loop {
+            while let Ok(join) = self.joins.try_recv() {
+                self.join(join);
+            }
+            if self.group.is_finished() {
+                let mut groups = self.groups.lock().unwrap();
+                match self.joins.try_recv() {
+                    Ok(join) => {
+                        drop(groups);
+                        self.join(join);
+                    }
+                    Err(_) => {
+                        groups.remove(&self.filename);
+                        return;
+                    }
+                }
+            }
+
+            // Joins are only picked up between packets, so we never wait longer than a tick
+            let timeout = self
+                .group
+                .deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(TICK)
+                .clamp(Duration::from_millis(1), TICK);
+            if let Err(error) = self.socket.set_read_timeout(Some(timeout)) {
+                self.logger.log(
+                    Level::Error,
+                    "Failed to set timeout",
+                    &[
+                        ("filename", Value::Text(&self.filename)),
+                        ("error", Value::Text(&error)),
+                    ],
+                );
+                return;
+            }
+
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, peer)) => {
+                    self.metrics.received(&buffer[..len]);
+                    let actions = self.group.handle(peer, &buffer[..len], Instant::now());
+                    self.act(actions);
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => self.logger.log(
+                    Level::Error,
+                    "Failed to receive",
+                    &[
+                        ("filename", Value::Text(&self.filename)),
+                        ("error", Value::Text(&error)),
+                    ],
+                ),
+            }
+
+            let actions = self.group.tick(Instant::now());
+            self.act(actions);
+        }
+    }
+
+    /// Add a client to the group. One without a [Slot] is resending its request, which only
+    /// gets it its OACK again if it is still in the group
+    fn join(&mut self, join: Join) {
+        match join.slot {
+            Some(slot) => {
+                self.slots.insert(join.client, slot);
+            }
+            None if !self.slots.contains_key(&join.client) => return,
+            None => {}
+        }
+        let actions = self.group.join(join.client, &join.options, Instant::now());
+        self.act(actions);
+    }
+
+    /// Send everything the group has for its clients, and log how each of them is getting on
+    fn act(&mut self, actions: Vec<Action>) {
+        let group = self.group.address();
+        for action in actions {
+            match action {
+                Action::Send(to, packet) => match self.socket.send_to(&packet, to) {
+                    Ok(len) => self.metrics.sent(len),
+                    Err(error) => self.logger.log(
+                        Level::Error,
+                        "Failed to send",
+                        &[("client", Value::Text(&to)), ("error", Value::Text(&error))],
+                    ),
+                },
+                Action::Joined { client, master } => self.logger.log(
+                    Level::Info,
+                    "Joined multicast group",
+                    &[
+                        ("client", Value::Text(&client)),
+                        ("filename", Value::Text(&self.filename)),
+                        ("group", Value::Text(&group)),
+                        ("master", Value::Text(&master)),
+                    ],
+                ),
+                Action::Master(client) => self.logger.log(
+                    Level::Info,
+                    "Multicast master",
+                    &[
+                        ("client", Value::Text(&client)),
+                        ("group", Value::Text(&group)),
+                    ],
+                ),
+                Action::Completed {
+                    client,
+                    bytes,
+                    duration,
+                } => {
+                    self.slots.remove(&client);
+                    self.metrics.completed(Direction::Read, duration);
+                    self.logger.log(
+                        Level::Info,
+                        "Completed",
+                        &[
+                            ("client", Value::Text(&client)),
+                            ("direction", Value::Text(&Direction::Read)),
+                            ("bytes", Value::from(bytes)),
+                            ("duration_ms", Value::Number(duration.as_millis() as u64)),
+                            ("group", Value::Text(&group)),
+                        ],
+                    );
+                }
+                Action::Failed(client, error) => {
+                    self.slots.remove(&client);
+                    self.metrics.failed(ErrorCode::from(&error) as u16);
+                    self.logger.log(
+                        Level::Error,
+                        "Transfer failed",
+                        &[
+                            ("client", Value::Text(&client)),
+                            ("code", Value::Number(ErrorCode::from(&error) as u64)),
+                            ("error", Value::Text(&error)),
+                            ("group", Value::Text(&group)),
+                        ],
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Slot {
+    /// Hold `client`'s place, which [TftpSessions::insert()] has already given it
+    fn new(sessions: Arc<TftpSessions>, client: SocketAddr, metrics: Arc<Metrics>) -> Self {
+        metrics.started();
+        Self {
+            sessions,
+            client,
+            metrics,
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.metrics.ended();
+        self.sessions.remove(&self.client);
+    }
+}
+
+impl TftpSessions {
+    const SHARDS: usize = 16;
+
+    fn new() -> Self {
+        Self {
+            shards: (0..Self::SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Every port on an IP address lands in the same shard so they can be counted together
+    fn shard(&self, client: &SocketAddr) -> &Mutex<HashMap<IpAddr, HashSet<SocketAddr>>> {
+        let mut hasher = DefaultHasher::new();
+        client.ip().hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % Self::SHARDS]
+    }
+
+    /// Take on a transfer with `client` as long as it is new and fits under both caps
+    fn insert(&self, client: SocketAddr, max: usize, max_client: usize) -> Result<(), Refused> {
+        let mut shard = self.shard(&client).lock().unwrap();
+        // Nothing is added for a client we refuse, or every address we turned away while full
+        // would stay in the map for good
+        let ports = shard.get(&client.ip());
+        if ports.is_some_and(|ports| ports.contains(&client)) {
+            return Err(Refused::Duplicate);
+        }
+        if ports.map_or(0, HashSet::len) >= max_client {
+            return Err(Refused::Full);
+        }
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .map_err(|_| Refused::Full)?;
+
+        shard.entry(client.ip()).or_default().insert(client);
+        Ok(())
+    }
+
+    fn remove(&self, client: &SocketAddr) {
+        let mut shard = self.shard(client).lock().unwrap();
+        let Some(ports) = shard.get_mut(&client.ip()) else {
+            return;
+        };
+        if ports.remove(client) {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+        }
+        if ports.is_empty() {
+            shard.remove(&client.ip());
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    machine::{Config, Input, Machine, Output, Storage, StorageRequest},
+    storage::{FileProvider, ReadFile, WriteFile},
+};
+use std::{collections::VecDeque, io, sync::Arc, time::Instant};
+
+/// The file a [Session] has open
+#[derive(Debug)]
+enum Open {
+    Read(Box<dyn ReadFile>),
+    Write(Box<dyn WriteFile>),
+}
+
+/// A [Machine] with its storage requests carried out by a [FileProvider] as soon as it makes
+/// them. Pass it every packet the client sends with [Session::handle()], call
+/// [Session::tick()] once [Session::deadline()] has passed, and act on whatever either of them
+/// return until [Session::is_finished()]. Neither ever returns an [Output::Storage]
+#[derive(Debug)]
+pub struct Session {
+    machine: Machine,
+    storage: Arc<dyn FileProvider>,
+    /// The file being served or uploaded, we only ever hold a window of it in memory
+    file: Option<Open>,
+}
+
+impl Session {
+    pub fn new(config: &Config, storage: Arc<dyn FileProvider>) -> Self {
+        Self {
+            machine: Machine::new(config),
+            storage,
+            file: None,
+        }
+    }
+
+    /// Work out what to send back for a packet from the client, which can be nothing or a
+    /// whole window of DATA packets
+    pub fn handle(&mut self, data: &[u8], now: Instant) -> Vec<Output> {
+        let outputs = self.machine.handle(Input::Packet(data), now);
+        self.drive(outputs, now)
+    }
+
+    /// If the client has not been heard from since the deadline we resend whatever we sent
+    /// them last, once we run out of retries the transfer fails
+    pub fn tick(&mut self, now: Instant) -> Vec<Output> {
+        let outputs = self.machine.handle(Input::Tick, now);
+        self.drive(outputs, now)
+    }
+
+    /// When [Session::tick()] next needs to be called, if we are waiting on the client
+    pub fn deadline(&self) -> Option<Instant> {
+        self.machine.deadline()
+    }
+
+    /// Once the final block of a transfer has been sent or received there is nothing left for
+    /// this [Session] to do and it can be dropped
+    pub fn is_finished(&self) -> bool {
+        self.machine.is_finished()
+    }
+
+    /// Carry out every storage request, feeding the results back in, until all that is left
+    /// is for the caller to do
+    fn drive(&mut self, outputs: Vec<Output>, now: Instant) -> Vec<Output> {
+        let mut outputs = VecDeque::from(outputs);
+        let mut done = Vec::new();
+
+        while let Some(output) = outputs.pop_front() {
+            match output {
+                Output::Storage(request) => {
+                    if let Some(result) = self.storage(request) {
+                        outputs.extend(self.machine.handle(Input::Storage(result), now));
+                    }
+                }
+                output => done.push(output),
+            }
+        }
+        done
+    }
+
+    /// Returns [None] for requests that have no answer
+    fn storage(&mut self, request: StorageRequest) -> Option<io::Result<Storage>> {
+        let result = match (request, &mut self.file) {
+            (StorageRequest::OpenRead(filename), _) => {
+                self.storage.open_read(&filename).map(|(file, size)| {
+                    self.file = Some(Open::Read(file));
+                    Storage::Opened { size }
+                })
+            }
+            (StorageRequest::OpenWrite(filename), _) => {
+                self.storage.open_write(&filename).map(|file| {
+                    self.file = Some(Open::Write(file));
+                    Storage::Opened { size: 0 }
+                })
+            }
+            (StorageRequest::Read { offset, length }, Some(Open::Read(file))) => {
+                let mut buffer = vec![0; length];
+                file.read_exact_at(&mut buffer, offset)
+                    .map(|_| Storage::Read(buffer))
+            }
+            (StorageRequest::Write { offset, data }, Some(Open::Write(file))) => {
+                file.write_all_at(&data, offset).map(|_| Storage::Written)
+            }
+            (StorageRequest::Commit, _) => match self.file.take() {
+                Some(Open::Write(file)) => file.commit().map(|_| Storage::Committed),
+                _ => Ok(Storage::Committed),
+            },
+            (StorageRequest::Abort, _) => {
+                if let Some(Open::Write(file)) = self.file.take() {
+                    file.abort();
+                }
+                return None;
+            }
+            _ => Err(io::ErrorKind::NotFound.into()),
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::MemoryFs,
+        tftp::{Acknowledgement, Data, Request, Serialise, Tftp, TftpOption},
+    };
+
+    fn session(memory: &MemoryFs) -> Session {
+        Session::new(&Config::default(), Arc::new(memory.clone()))
+    }
+
+    /// Hand the session a packet and return the packets it sends back
+    fn send(session: &mut Session, packet: Tftp) -> Vec<Vec<u8>> {
+        session
+            .handle(&packet.serialise(), Instant::now())
+            .into_iter()
+            .filter_map(|output| match output {
+                Output::Send(packet) => Some(packet),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn request<'a>(filename: &'a str, mode: &'a str, options: Vec<TftpOption>) -> Request<'a> {
+        Request {
+            filename,
+            mode,
+            options,
+        }
+    }
+
+    fn ack(session: &mut Session, block: u16) -> Vec<Vec<u8>> {
+        send(session, Tftp::Acknowledgement(Acknowledgement { block }))
+    }
+
+    /// Append the contents of every DATA packet to `file`, returning the last block number
+    fn received(sent: &[Vec<u8>], file: &mut Vec<u8>) -> u16 {
+        sent.iter()
+            .map(|packet| match Tftp::parse(packet) {
+                Ok(Tftp::Data(data)) => {
+                    file.extend_from_slice(data.data);
+                    data.block
+                }
+                packet => panic!("expected DATA, got {packet:?}"),
+            })
+            .last()
+            .unwrap()
+    }
+
+    fn acknowledged(sent: &[Vec<u8>]) -> u16 {
+        match Tftp::parse(&sent[0]) {
+            Ok(Tftp::Acknowledgement(ack)) => ack.block,
+            packet => panic!("expected ACK, got {packet:?}"),
+        }
+    }
+
+    /// Acknowledge each window the session sends until it is done, returning the file
+    fn download(session: &mut Session, mut sent: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut file = Vec::new();
+        if matches!(Tftp::parse(&sent[0]), Ok(Tftp::OptionAcknowledgement(_))) {
+            sent = ack(session, 0);
+        }
+        while !session.is_finished() {
+            let block = received(&sent, &mut file);
+            sent = ack(session, block);
+        }
+        file
+    }
+
+    #[test]
+    fn reads_a_file_from_memory() {
+        let memory = MemoryFs::new();
+        let data: Vec<u8> = (0..1200).map(|byte| byte as u8).collect();
+        memory.insert("boot/pxelinux.0", data.clone()).unwrap();
+
+        let mut session = session(&memory);
+        let sent = send(
+            &mut session,
+            Tftp::ReadRequest(request("boot/pxelinux.0", "octet", Vec::new())),
+        );
+        assert_eq!(download(&mut session, sent), data);
+    }
+
+    #[test]
+    fn reads_a_file_from_memory_as_netascii() {
+        let memory = MemoryFs::new();
+        memory.insert("motd", &b"one\ntwo\rthree"[..]).unwrap();
+
+        let mut session = session(&memory);
+        let sent = send(
+            &mut session,
+            Tftp::ReadRequest(request("motd", "netascii", Vec::new())),
+        );
+        assert_eq!(download(&mut session, sent), b"one\r\ntwo\r\0three");
+    }
+
+    #[test]
+    fn reads_a_file_from_memory_a_window_at_a_time() {
+        let memory = MemoryFs::new();
+        let data: Vec<u8> = (0..3000).map(|byte| byte as u8).collect();
+        memory.insert("kernel", data.clone()).unwrap();
+
+        let mut session = session(&memory);
+        let sent = send(
+            &mut session,
+            Tftp::ReadRequest(request("kernel", "octet", vec![TftpOption::WindowSize(4)])),
+        );
+        assert!(matches!(
+            Tftp::parse(&sent[0]),
+            Ok(Tftp::OptionAcknowledgement(_))
+        ));
+        let sent = ack(&mut session, 0);
+        assert_eq!(sent.len(), 4);
+        assert_eq!(download(&mut session, sent), data);
+    }
+
+    #[test]
+    fn writes_a_file_to_memory() {
+        let memory = MemoryFs::new();
+        let data: Vec<u8> = (0..700).map(|byte| byte as u8).collect();
+
+        let mut session = session(&memory);
+        let sent = send(
+            &mut session,
+            Tftp::WriteRequest(request("upload.bin", "octet", Vec::new())),
+        );
+        assert_eq!(acknowledged(&sent), 0);
+        for (index, block) in data.chunks(512).enumerate() {
+            let block_number = index as u16 + 1;
+            let sent = send(
+                &mut session,
+                Tftp::Data(Data {
+                    block: block_number,
+                    data: block,
+                }),
+            );
+            assert_eq!(acknowledged(&sent), block_number);
+        }
+        assert!(session.is_finished());
+        assert_eq!(&*memory.get("upload.bin").unwrap(), data);
+    }
+
+    #[test]
+    fn writes_a_file_to_memory_as_netascii() {
+        let memory = MemoryFs::new();
+        // The CR LF is split across blocks, so the decoder has to hold on to the CR
+        let mut data = vec![b'x'; 511];
+        data.extend_from_slice(b"\r\nnext\r\0line");
+
+        let mut session = session(&memory);
+        send(
+            &mut session,
+            Tftp::WriteRequest(request("notes.txt", "netascii", Vec::new())),
+        );
+        for (index, block) in data.chunks(512).enumerate() {
+            send(
+                &mut session,
+                Tftp::Data(Data {
+                    block: index as u16 + 1,
+                    data: block,
+                }),
+            );
+        }
+        assert!(session.is_finished());
+
+        let mut expected = vec![b'x'; 511];
+        expected.extend_from_slice(b"\nnext\rline");
+        assert_eq!(&*memory.get("notes.txt").unwrap(), expected);
+    }
+
+    #[test]
+    fn writes_a_file_to_memory_a_window_at_a_time() {
+        let memory = MemoryFs::new();
+        let data: Vec<u8> = (0..2100).map(|byte| byte as u8).collect();
+
+        let mut session = session(&memory);
+        let sent = send(
+            &mut session,
+            Tftp::WriteRequest(request(
+                "upload.bin",
+                "octet",
+                vec![TftpOption::WindowSize(4)],
+            )),
+        );
+        assert!(matches!(
+            Tftp::parse(&sent[0]),
+            Ok(Tftp::OptionAcknowledgement(_))
+        ));
+
+        // Only the last block of each window, and the final block, are acknowledged
+        let mut acks = Vec::new();
+        for (index, block) in data.chunks(512).enumerate() {
+            let sent = send(
+                &mut session,
+                Tftp::Data(Data {
+                    block: index as u16 + 1,
+                    data: block,
+                }),
+            );
+            if !sent.is_empty() {
+                acks.push(acknowledged(&sent));
+            }
+        }
+        assert_eq!(acks, [4, 5]);
+        assert!(session.is_finished());
+        assert_eq!(&*memory.get("upload.bin").unwrap(), data);
+    }
+}
@@ -0,0 +1,610 @@
+use crate::log::{Level, Logger, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::SystemTime,
+};
+
+/// Where the server finds the files it serves and puts the ones it is sent. Filenames are
+/// exactly as the client sent them, it is up to each provider to refuse any that would reach
+/// outside what it is serving
+pub trait FileProvider: fmt::Debug + Send + Sync {
+    /// Open a file to be served, along with its size in bytes. The size is what a client
+    /// asking for `tsize` is told and how we know which block is the last, so it has to be
+    /// known before the first block is read
+    fn open_read(&self, filename: &str) -> io::Result<(Box<dyn ReadFile>, u64)>;
+
+    /// Create a file for an upload. A file that already exists is never overwritten
+    fn open_write(&self, filename: &str) -> io::Result<Box<dyn WriteFile>>;
+
+    fn metadata(&self, filename: &str) -> io::Result<Metadata>;
+
+    fn exists(&self, filename: &str) -> bool {
+        self.metadata(filename).is_ok()
+    }
+}
+
+/// A file opened by [FileProvider::open_read()]. Reads can come from any offset in any order,
+/// and from more than one thread when a file is being multicast
+pub trait ReadFile: fmt::Debug + Send {
+    /// Fill all of `buffer` from `offset`
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+/// A file created by [FileProvider::open_write()], which only exists for good once it has been
+/// committed
+pub trait WriteFile: fmt::Debug + Send {
+    /// Write all of `data` at `offset`
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Every block has been written, make sure the upload is kept
+    fn commit(self: Box<Self>) -> io::Result<()>;
+
+    /// The upload never finished, get rid of whatever was written
+    fn abort(self: Box<Self>);
+}
+
+/// What a [FileProvider] knows about a file without opening it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Length in bytes
+    pub size: u64,
+    /// [None] if the provider doesn't keep track
+    pub modified: Option<SystemTime>,
+}
+
+/// Files in a directory on the local filesystem, the default [FileProvider]
+#[derive(Debug, Clone)]
+pub struct LocalFs {
+    /// The directory filenames in requests are looked up in
+    root: PathBuf,
+    /// Where uploads in progress are going, so two clients can't upload the same file at once
+    uploading: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// A file being uploaded. It is written under a hidden name next to `path`, so nobody can read
+/// it half written, and only moved to `path` once it is committed. Whatever is left under the
+/// hidden name is removed when it is dropped, which is also when the upload stops counting as
+/// in progress
+#[derive(Debug)]
+struct Upload {
+    file: File,
+    temporary: PathBuf,
+    path: PathBuf,
+    uploading: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Files held in memory, filled in with [MemoryFs::insert()] or copied from a directory with
+/// [MemoryFs::snapshot()]. Uploads are kept here too and can be looked at with
+/// [MemoryFs::get()], nothing is ever written to disk. Clones share the same files
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    files: Arc<RwLock<MemoryFiles>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFiles {
+    /// Keyed by the filename's components joined with `/`
+    files: HashMap<String, MemoryFile>,
+    /// Uploads in progress, so two clients can't upload the same file at once
+    uploading: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryFile {
+    data: Arc<[u8]>,
+    modified: SystemTime,
+}
+
+/// A file being uploaded to a [MemoryFs], which only appears there once it is committed
+#[derive(Debug)]
+struct MemoryUpload {
+    fs: MemoryFs,
+    name: String,
+    data: Vec<u8>,
+}
+
+impl LocalFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            uploading: Arc::default(),
+        }
+    }
+
+    /// Find `filename` under the root
+    fn resolve(&self, filename: &str) -> io::Result<PathBuf> {
+        let mut path = self.root.clone();
+        path.extend(components(filename)?);
+        Ok(path)
+    }
+
+    /// Make sure a path is still inside the root once symlinks have been followed
+    fn contained(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = path.canonicalize()?;
+        if !path.starts_with(self.root.canonicalize()?) {
+            return Err(denied("Path leads outside the root directory"));
+        }
+        Ok(path)
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every regular file under `root`, copied into memory. Symlinks to files are followed as
+    /// long as they stay under `root`, just as [LocalFs] would serve them, but symlinks to
+    /// directories are not, so a loop can't keep us reading forever. Symlinks that lead
+    /// nowhere or out of `root`, and files we can't read, are logged and left out
+    pub fn snapshot(root: impl AsRef<Path>, logger: Logger) -> io::Result<Self> {
+        let memory = Self::new();
+        let jail = root.as_ref().canonicalize()?;
+        let mut directories = vec![(root.as_ref().to_path_buf(), String::new())];
+
+        while let Some((directory, prefix)) = directories.pop() {
+            for entry in fs::read_dir(directory)? {
+                let entry = entry?;
+                let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+                if entry.file_type()?.is_dir() {
+                    directories.push((entry.path(), format!("{name}/")));
+                    continue;
+                }
+                match Self::copy(&entry.path(), &jail) {
+                    Ok(Some(data)) => memory.insert(&name, data)?,
+                    Ok(None) => {}
+                    Err(error) => logger.log(
+                        Level::Warn,
+                        "Skipped file",
+                        &[
+                            ("path", Value::Text(&entry.path().display())),
+                            ("error", Value::Text(&error)),
+                        ],
+                    ),
+                }
+            }
+        }
+        Ok(memory)
+    }
+
+    /// The contents of the file at `path` if it is a regular file, once symlinks have been
+    /// followed, inside `jail`
+    fn copy(path: &Path, jail: &Path) -> io::Result<Option<Vec<u8>>> {
+        let path = path.canonicalize()?;
+        if !path.starts_with(jail) {
+            return Err(denied("Path leads outside the root directory"));
+        }
+        if !fs::metadata(&path)?.is_file() {
+            return Ok(None);
+        }
+        fs::read(path).map(Some)
+    }
+
+    /// Add a file, replacing any already there. `filename` is checked just like one from a
+    /// request, so it can't be absolute or contain `..`
+    pub fn insert(&self, filename: &str, data: impl Into<Arc<[u8]>>) -> io::Result<()> {
+        let file = MemoryFile {
+            data: data.into(),
+            modified: SystemTime::now(),
+        };
+        self.write().files.insert(normalise(filename)?, file);
+        Ok(())
+    }
+
+    /// The contents of a file, including one that has been uploaded
+    pub fn get(&self, filename: &str) -> Option<Arc<[u8]>> {
+        let name = normalise(filename).ok()?;
+        self.read().files.get(&name).map(|file| file.data.clone())
+    }
+
+    pub fn remove(&self, filename: &str) -> Option<Arc<[u8]>> {
+        let name = normalise(filename).ok()?;
+        self.write().files.remove(&name).map(|file| file.data)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, MemoryFiles> {
+        self.files.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, MemoryFiles> {
+        self.files.write().unwrap()
+    }
+
+    fn file(&self, filename: &str) -> io::Result<MemoryFile> {
+        self.read()
+            .files
+            .get(&normalise(filename)?)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+impl FileProvider for MemoryFs {
+    fn open_read(&self, filename: &str) -> io::Result<(Box<dyn ReadFile>, u64)> {
+        let data = self.file(filename)?.data;
+        let size = data.len() as u64;
+        Ok((Box::new(data), size))
+    }
+
+    fn open_write(&self, filename: &str) -> io::Result<Box<dyn WriteFile>> {
+        let name = normalise(filename)?;
+        let mut files = self.write();
+        if files.files.contains_key(&name) || !files.uploading.insert(name.clone()) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+
+        Ok(Box::new(MemoryUpload {
+            fs: self.clone(),
+            name,
+            data: Vec::new(),
+        }))
+    }
+
+    fn metadata(&self, filename: &str) -> io::Result<Metadata> {
+        let file = self.file(filename)?;
+        Ok(Metadata {
+            size: file.data.len() as u64,
+            modified: Some(file.modified),
+        })
+    }
+}
+
+impl FileProvider for LocalFs {
+    /// Only regular files are served, never directories or devices. That is checked before
+    /// the file is opened, as opening a FIFO blocks until something writes to it, and again
+    /// after in case it was swapped in between
+    fn open_read(&self, filename: &str) -> io::Result<(Box<dyn ReadFile>, u64)> {
+        let path = self.contained(&self.resolve(filename)?)?;
+        if !fs::metadata(&path)?.is_file() {
+            return Err(denied("Not a regular file"));
+        }
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(denied("Not a regular file"));
+        }
+        Ok((Box::new(file), metadata.len()))
+    }
+
+    /// The file can not exist yet, so it is the directory it goes in that has to be inside the
+    /// root. Anything already there is refused, including a symlink, and checked for again
+    /// when the upload is committed
+    fn open_write(&self, filename: &str) -> io::Result<Box<dyn WriteFile>> {
+        static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+        let path = self.resolve(filename)?;
+        let (Some(directory), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(denied("Not a regular file"));
+        };
+        let path = self.contained(directory)?.join(name);
+        let mut uploading = self.uploading.lock().unwrap();
+        if fs::symlink_metadata(&path).is_ok() || uploading.contains(&path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+
+        let temporary = path.with_file_name(format!(
+            ".{}.{}-{}.upload",
+            name.to_string_lossy(),
+            process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create_new(&temporary)?;
+        uploading.insert(path.clone());
+        Ok(Box::new(Upload {
+            file,
+            temporary,
+            path,
+            uploading: self.uploading.clone(),
+        }))
+    }
+
+    fn metadata(&self, filename: &str) -> io::Result<Metadata> {
+        let path = self.contained(&self.resolve(filename)?)?;
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(denied("Not a regular file"));
+        }
+        Ok(Metadata {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+impl ReadFile for File {
+    /// Reads never move the file cursor
+    fn read_exact_at(&self, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buffer.is_empty() {
+            #[cfg(unix)]
+            let read = std::os::unix::fs::FileExt::read_at(self, buffer, offset);
+            #[cfg(windows)]
+            let read = std::os::windows::fs::FileExt::seek_read(self, buffer, offset);
+
+            match read {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => {
+                    buffer = &mut buffer[len..];
+                    offset += len as u64;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReadFile for Arc<[u8]> {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.get(offset..)?.get(..buffer.len()))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buffer.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl WriteFile for Upload {
+    /// Writes never move the file cursor
+    fn write_all_at(&mut self, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+        while !data.is_empty() {
+            #[cfg(unix)]
+            let written = std::os::unix::fs::FileExt::write_at(&self.file, data, offset);
+            #[cfg(windows)]
+            let written = std::os::windows::fs::FileExt::seek_write(&self.file, data, offset);
+
+            match written {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    data = &data[len..];
+                    offset += len as u64;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure everything we have received from the client is on disk, then give it its
+    /// real name. That is done with a hard link rather than a rename, as a link never replaces
+    /// a file someone else put there while we were uploading
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::hard_link(&self.temporary, &self.path)
+    }
+
+    fn abort(self: Box<Self>) {}
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.temporary);
+        self.uploading.lock().unwrap().remove(&self.path);
+    }
+}
+
+impl WriteFile for MemoryUpload {
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        let end = start + data.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let file = MemoryFile {
+            data: self.data.into(),
+            modified: SystemTime::now(),
+        };
+        let mut files = self.fs.write();
+        files.uploading.remove(&self.name);
+        files.files.insert(self.name, file);
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) {
+        self.fs.write().uploading.remove(&self.name);
+    }
+}
+
+/// Split `filename` into the names of the directories it is in and its own, refusing absolute
+/// paths and `..` so a client can never name anything outside what we serve. PXE clients often
+/// separate directories with backslashes so we accept either
+fn components(filename: &str) -> io::Result<Vec<&str>> {
+    let drive = filename.as_bytes().get(1) == Some(&b':');
+    if filename.starts_with(['/', '\\']) || drive {
+        return Err(denied("Absolute paths are not allowed"));
+    }
+
+    let mut components = Vec::new();
+    for component in filename.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => return Err(denied("Parent directories are not allowed")),
+            component => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+/// The key a file is kept under by a provider that isn't backed by a directory
+pub(crate) fn normalise(filename: &str) -> io::Result<String> {
+    Ok(components(filename)?.join("/"))
+}
+
+fn denied(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::Format;
+
+    const QUIET: Logger = Logger {
+        level: None,
+        format: Format::Text,
+    };
+
+    fn denied_with(filename: &str) -> String {
+        let error = components(filename).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        error.to_string()
+    }
+
+    #[test]
+    fn accepts_backslashes_like_forward_slashes() {
+        assert_eq!(
+            components(r"boot\x64\pxelinux.0").unwrap(),
+            ["boot", "x64", "pxelinux.0"]
+        );
+        assert_eq!(
+            normalise(r"boot/x64\pxelinux.0").unwrap(),
+            "boot/x64/pxelinux.0"
+        );
+    }
+
+    #[test]
+    fn skips_empty_and_current_directory_components() {
+        assert_eq!(
+            normalise(r"boot//.\x64\\.\/grub.cfg").unwrap(),
+            "boot/x64/grub.cfg"
+        );
+        assert_eq!(normalise("./pxelinux.0").unwrap(), "pxelinux.0");
+        assert!(components(".").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_parent_directories() {
+        let reason = "Parent directories are not allowed";
+        assert_eq!(denied_with(".."), reason);
+        assert_eq!(denied_with("../etc/passwd"), reason);
+        assert_eq!(denied_with(r"boot\..\..\etc\passwd"), reason);
+        assert_eq!(denied_with("boot/x64/.."), reason);
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        let reason = "Absolute paths are not allowed";
+        assert_eq!(denied_with("/etc/passwd"), reason);
+        assert_eq!(denied_with(r"\boot\pxelinux.0"), reason);
+        assert_eq!(denied_with(r"C:\Windows\win.ini"), reason);
+        assert_eq!(denied_with("c:boot.ini"), reason);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_to_open_anything_but_a_regular_file() {
+        let root = std::env::temp_dir().join(format!("tftp3o-fifo-{}", std::process::id()));
+        fs::create_dir_all(root.join("boot")).unwrap();
+        let made = std::process::Command::new("mkfifo")
+            .arg(root.join("pipe"))
+            .status();
+
+        let local = LocalFs::new(&root);
+        let pipe = local.open_read("pipe").map(|_| ());
+        let directory = local.open_read("boot").map(|_| ());
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(made.unwrap().success());
+        assert_eq!(pipe.unwrap_err().to_string(), "Not a regular file");
+        assert_eq!(directory.unwrap_err().to_string(), "Not a regular file");
+    }
+
+    #[test]
+    fn keeps_uploads_hidden_until_they_are_committed() {
+        let root = std::env::temp_dir().join(format!("tftp3o-upload-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let local = LocalFs::new(&root);
+
+        let mut upload = local.open_write("pxelinux.0").unwrap();
+        upload.write_all_at(b"boot", 0).unwrap();
+        let reading = local.open_read("pxelinux.0").map(|_| ());
+        let again = local.open_write("pxelinux.0").map(|_| ());
+        upload.commit().unwrap();
+        let committed = fs::read(root.join("pxelinux.0"));
+
+        // Someone else's file turns up while we are uploading
+        let mut upload = local.open_write("grub.cfg").unwrap();
+        upload.write_all_at(b"ours", 0).unwrap();
+        fs::write(root.join("grub.cfg"), "theirs").unwrap();
+        let clash = upload.commit();
+        let kept = fs::read(root.join("grub.cfg"));
+
+        let mut upload = local.open_write("aborted").unwrap();
+        upload.write_all_at(b"half", 0).unwrap();
+        upload.abort();
+
+        let mut left: Vec<_> = fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(reading.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(again.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(committed.unwrap(), b"boot");
+        assert_eq!(clash.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(kept.unwrap(), b"theirs");
+        assert_eq!(left, ["grub.cfg", "pxelinux.0"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshot_skips_symlinks_out_of_the_root() {
+        let base = std::env::temp_dir().join(format!("tftp3o-snapshot-{}", std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(base.join("secret"), "outside").unwrap();
+        fs::write(root.join("pxelinux.0"), "inside").unwrap();
+        std::os::unix::fs::symlink(base.join("secret"), root.join("secret")).unwrap();
+        std::os::unix::fs::symlink(root.join("pxelinux.0"), root.join("default")).unwrap();
+
+        let memory = MemoryFs::snapshot(&root, QUIET);
+        fs::remove_dir_all(&base).unwrap();
+        let memory = memory.unwrap();
+        assert_eq!(&*memory.get("pxelinux.0").unwrap(), b"inside");
+        assert_eq!(&*memory.get("default").unwrap(), b"inside");
+        assert!(memory.get("secret").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshot_skips_symlinks_that_lead_nowhere() {
+        let root = std::env::temp_dir().join(format!("tftp3o-dangling-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("pxelinux.0"), "inside").unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("loop"), root.join("loop")).unwrap();
+
+        let memory = MemoryFs::snapshot(&root, QUIET);
+        fs::remove_dir_all(&root).unwrap();
+        let memory = memory.unwrap();
+        assert_eq!(&*memory.get("pxelinux.0").unwrap(), b"inside");
+        assert!(memory.get("dangling").is_none());
+        assert!(memory.get("loop").is_none());
+    }
+
+    #[test]
+    fn keeps_dots_inside_names() {
+        assert_eq!(
+            normalise("..hidden/file..name").unwrap(),
+            "..hidden/file..name"
+        );
+    }
+}
@@ -1,50 +1,86 @@
-use crate::error::Error;
-use std::{ffi::CStr, fs::File, io::Read, str::from_utf8};
+use crate::error::{Error, ParseError, ProtocolError};
+use std::{ffi::CStr, fmt, io, str::from_utf8};
+
+/// Big enough for a DATA packet carrying the largest block size RFC 2348 allows
+pub(crate) const UDP_BUFFER_SIZE: usize = 65468;
+
+fn slice_to_usize(option: &[u8], slice: &[u8]) -> Result<usize, ParseError> {
+    from_utf8(slice)
+        .ok()
+        .and_then(|str| str.parse::<usize>().ok())
+        .ok_or_else(|| ParseError::InvalidOptionValue {
+            option: String::from_utf8_lossy(option).into(),
+            value: String::from_utf8_lossy(slice).into(),
+        })
+}
 
-fn slice_to_usize(slice: &[u8]) -> usize {
-    if let Ok(str) = from_utf8(slice) {
-        if let Ok(num) = str.parse::<usize>() {
-            return num;
-        }
+fn slice_to_u16(slice: &[u8], ptr: usize) -> Result<u16, ParseError> {
+    match slice.get(ptr..ptr + 2) {
+        Some(&[high, low]) => Ok(u16::from_be_bytes([high, low])),
+        _ => Err(ParseError::Truncated {
+            expected: ptr + 2,
+            actual: slice.len(),
+        }),
     }
-    unimplemented!("Could not parse {slice:?} into option value");
 }
 
-trait Serialise {
-    fn serialise(&self) -> Vec<u8>;
-}
+fn slice_to_str(slice: &[u8], ptr: usize) -> Result<&str, ParseError> {
+    let slice = slice.get(ptr..).ok_or(ParseError::Truncated {
+        expected: ptr,
+        actual: slice.len(),
+    })?;
 
-#[derive(Debug)]
-pub struct Session {
-    data: Vec<u8>,
-    block_size: usize,
-    file_size: usize,
-    window_size: usize,
+    CStr::from_bytes_until_nul(slice)
+        .map_err(|_| ParseError::UnterminatedString)?
+        .to_str()
+        .map_err(|_| ParseError::InvalidUtf8)
 }
-impl Session {
-    const DEFAULT_BLOCK_SIZE: usize = 512;
-    const DEFAULT_WINDOW_SIZE: usize = 1;
-    pub fn new() -> Self {
-        Self {
-            data: Vec::new(),
-            block_size: Self::DEFAULT_BLOCK_SIZE,
-            file_size: 0,
-            window_size: Self::DEFAULT_WINDOW_SIZE,
-        }
-    }
+
+/// Turn a packet, or part of one, into the bytes that go on the wire
+pub trait Serialise {
+    fn serialise(&self) -> Vec<u8>;
 }
 
+/// The options a client can negotiate in a request, and we confirm in an OACK (RFC 2347)
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum TftpOption {
+pub enum TftpOption {
+    /// RFC 2349 size of the file in bytes, a client reading a file sends 0 and we fill it in
     TransferSize(usize),
+    /// RFC 2348 number of bytes in each DATA packet
     BlockSize(usize),
+    /// RFC 7440 number of DATA packets sent per ACK
     WindowSize(usize),
+    /// RFC 2349 retransmit timeout in seconds
+    Timeout(usize),
+    /// The tftp-hpa extension of [TftpOption::Timeout] for clients that want to retransmit
+    /// more often than once a second, in microseconds
+    TimeoutMicros(usize),
+    /// The block number to wrap around to after block 65535, either 0 or 1
+    Rollover(usize),
+    /// RFC 2090 multicast transfer, empty in a request and `address,port,master` in an OACK
+    Multicast(String),
+    /// An option we don't support, kept with its value as sent and never acknowledged
+    Unknown(String, String),
 }
 
 impl Serialise for TftpOption {
     fn serialise(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         let value = match self {
+            TftpOption::Multicast(value) => {
+                bytes.extend_from_slice(TftpOption::MULTICAST);
+                bytes.push(Self::NULL);
+                bytes.extend_from_slice(value.as_bytes());
+                bytes.push(Self::NULL);
+                return bytes;
+            }
+            TftpOption::Unknown(name, value) => {
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(Self::NULL);
+                bytes.extend_from_slice(value.as_bytes());
+                bytes.push(Self::NULL);
+                return bytes;
+            }
             TftpOption::TransferSize(value) => {
                 bytes.extend_from_slice(TftpOption::TSIZE);
                 value
@@ -57,6 +93,18 @@ impl Serialise for TftpOption {
                 bytes.extend_from_slice(TftpOption::WINDOWSIZE);
                 value
             }
+            TftpOption::Timeout(value) => {
+                bytes.extend_from_slice(TftpOption::TIMEOUT);
+                value
+            }
+            TftpOption::TimeoutMicros(value) => {
+                bytes.extend_from_slice(TftpOption::UTIMEOUT);
+                value
+            }
+            TftpOption::Rollover(value) => {
+                bytes.extend_from_slice(TftpOption::ROLLOVER);
+                value
+            }
         };
         bytes.push(Self::NULL);
         bytes.extend_from_slice(value.to_string().as_bytes());
@@ -66,145 +114,303 @@ impl Serialise for TftpOption {
     }
 }
 
+/// `name=value`, as it would appear in a request
+impl fmt::Display for TftpOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, value): (&[u8], &dyn fmt::Display) = match self {
+            TftpOption::TransferSize(value) => (Self::TSIZE, value),
+            TftpOption::BlockSize(value) => (Self::BLKSIZE, value),
+            TftpOption::WindowSize(value) => (Self::WINDOWSIZE, value),
+            TftpOption::Timeout(value) => (Self::TIMEOUT, value),
+            TftpOption::TimeoutMicros(value) => (Self::UTIMEOUT, value),
+            TftpOption::Rollover(value) => (Self::ROLLOVER, value),
+            TftpOption::Multicast(value) => (Self::MULTICAST, value),
+            TftpOption::Unknown(name, value) => (name.as_bytes(), value),
+        };
+        write!(f, "{}={value}", String::from_utf8_lossy(name))
+    }
+}
+
 impl TftpOption {
     const TSIZE: &[u8] = &[0x74, 0x73, 0x69, 0x7a, 0x65];
     const BLKSIZE: &[u8] = &[0x62, 0x6c, 0x6b, 0x73, 0x69, 0x7a, 0x65];
     const WINDOWSIZE: &[u8] = &[0x77, 0x69, 0x6e, 0x64, 0x6f, 0x77, 0x73, 0x69, 0x7a, 0x65];
+    const TIMEOUT: &[u8] = &[0x74, 0x69, 0x6d, 0x65, 0x6f, 0x75, 0x74];
+    const UTIMEOUT: &[u8] = &[0x75, 0x74, 0x69, 0x6d, 0x65, 0x6f, 0x75, 0x74];
+    const ROLLOVER: &[u8] = &[0x72, 0x6f, 0x6c, 0x6c, 0x6f, 0x76, 0x65, 0x72];
+    const MULTICAST: &[u8] = &[0x6d, 0x75, 0x6c, 0x74, 0x69, 0x63, 0x61, 0x73, 0x74];
     const END: &[u8] = &[];
     const NULL: u8 = 0x00;
 
-    fn parse(data: &[u8]) -> Vec<TftpOption> {
-        let mut options = Vec::with_capacity(5);
+    /// Option names are case insensitive (RFC 2347). If a client sends the same option twice
+    /// the first one wins and the rest are dropped. Every name and value has to end with a
+    /// NULL, a list that stops short of one is [ParseError::UnterminatedString]
+    fn parse(data: &[u8]) -> Result<Vec<TftpOption>, ParseError> {
+        let mut options: Vec<TftpOption> = Vec::with_capacity(5);
+        if data.is_empty() {
+            return Ok(options);
+        }
+        let data = data
+            .strip_suffix(&[Self::NULL])
+            .ok_or(ParseError::UnterminatedString)?;
         let mut options_raw = data.split(|chr| *chr == Self::NULL);
 
-        while let Some(option) = options_raw.next() {
-            match option {
-                Self::TSIZE => options.push(TftpOption::TransferSize(slice_to_usize(
-                    options_raw.next().unwrap(),
-                ))),
-                Self::BLKSIZE => options.push(TftpOption::BlockSize(slice_to_usize(
-                    options_raw.next().unwrap(),
-                ))),
-                Self::WINDOWSIZE => options.push(TftpOption::WindowSize(slice_to_usize(
-                    options_raw.next().unwrap(),
-                ))),
-                Self::END => return options,
-                _ => unimplemented!("We dont handle option {option:X?}"),
-            };
+        while let Some(name) = options_raw.next() {
+            if name == Self::END {
+                return Ok(options);
+            }
+
+            let value = options_raw.next().ok_or(ParseError::UnterminatedString)?;
+            let option = Self::parse_one(name, value)?;
+
+            if !options.iter().any(|seen| seen.same_option(&option)) {
+                options.push(option);
+            }
+        }
+        Ok(options)
+    }
+
+    fn parse_one(name: &[u8], value: &[u8]) -> Result<TftpOption, ParseError> {
+        let option: fn(usize) -> TftpOption = match name {
+            name if name.eq_ignore_ascii_case(Self::TSIZE) => TftpOption::TransferSize,
+            name if name.eq_ignore_ascii_case(Self::BLKSIZE) => TftpOption::BlockSize,
+            name if name.eq_ignore_ascii_case(Self::WINDOWSIZE) => TftpOption::WindowSize,
+            name if name.eq_ignore_ascii_case(Self::TIMEOUT) => TftpOption::Timeout,
+            name if name.eq_ignore_ascii_case(Self::UTIMEOUT) => TftpOption::TimeoutMicros,
+            name if name.eq_ignore_ascii_case(Self::ROLLOVER) => TftpOption::Rollover,
+            name if name.eq_ignore_ascii_case(Self::MULTICAST) => {
+                return Ok(TftpOption::Multicast(String::from_utf8_lossy(value).into()))
+            }
+            _ => {
+                return Ok(TftpOption::Unknown(
+                    String::from_utf8_lossy(name).into(),
+                    String::from_utf8_lossy(value).into(),
+                ))
+            }
+        };
+        Ok(option(slice_to_usize(name, value)?))
+    }
+
+    /// Whether two options have the same name, whatever their values
+    fn same_option(&self, other: &TftpOption) -> bool {
+        match (self, other) {
+            (TftpOption::Unknown(name, _), TftpOption::Unknown(other, _)) => {
+                name.eq_ignore_ascii_case(other)
+            }
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+/// How the file in a request is to be transferred, case doesn't matter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Raw bytes, exactly as they are stored
+    Octet,
+    /// Text, with line endings translated to CR LF on the wire
+    Netascii,
+}
+
+impl Mode {
+    const OCTET: &str = "octet";
+    const NETASCII: &str = "netascii";
+}
+
+impl TryFrom<&str> for Mode {
+    type Error = ParseError;
+
+    /// RFC 1350's mail mode is obsolete so we treat it like any other mode we don't know
+    fn try_from(mode: &str) -> Result<Self, ParseError> {
+        if mode.eq_ignore_ascii_case(Self::OCTET) {
+            Ok(Self::Octet)
+        } else if mode.eq_ignore_ascii_case(Self::NETASCII) {
+            Ok(Self::Netascii)
+        } else {
+            Err(ParseError::UnsupportedMode(mode.into()))
         }
-        options
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-enum OpCode {
+/// The first two bytes of every TFTP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum OpCode {
     ReadRequest = 1,
+    WriteRequest = 2,
     Data = 3,
     Acknowledgement = 4,
-    ErrorCode = 5,
+    Error = 5,
     OptionAcknowledgement = 6,
 }
 
-impl TryFrom<u8> for OpCode {
-    type Error = Error;
+impl TryFrom<u16> for OpCode {
+    type Error = ParseError;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u16) -> Result<Self, ParseError> {
         match value {
             1 => Ok(Self::ReadRequest),
+            2 => Ok(Self::WriteRequest),
             3 => Ok(Self::Data),
             4 => Ok(Self::Acknowledgement),
-            5 => Ok(Self::ErrorCode),
+            5 => Ok(Self::Error),
             6 => Ok(Self::OptionAcknowledgement),
-            value => Err(Error::InvalidTftpOpCode(value)),
+            value => Err(ParseError::InvalidOpCode(value)),
         }
     }
 }
 
 impl Serialise for OpCode {
     fn serialise(&self) -> Vec<u8> {
-        let mut bytes = [0, 0];
-        bytes[1] = *self as u8;
-        bytes.to_vec()
+        (*self as u16).to_be_bytes().to_vec()
     }
 }
 
+/// Every TFTP packet, borrowing filenames and file data from the datagram it was parsed from.
+/// Use [Tftp::parse()] and [Serialise::serialise()] to go to and from the wire
 #[derive(Debug)]
 pub enum Tftp<'tftp> {
-    ReadRequest(ReadRequest<'tftp>),
+    ReadRequest(Request<'tftp>),
+    WriteRequest(Request<'tftp>),
     Acknowledgement(Acknowledgement),
     OptionAcknowledgement(OptionAcknowledgement),
     Data(Data<'tftp>),
+    Error(ErrorMessage),
 }
 
-#[derive(Debug)]
-pub struct Data<'data> {
-    block: u16,
-    data: &'data [u8],
+/// The error codes from RFC 1350, plus option negotiation failure from RFC 2347
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    NotDefined = 0,
+    FileNotFound = 1,
+    AccessViolation = 2,
+    DiskFull = 3,
+    IllegalOperation = 4,
+    UnknownTransferId = 5,
+    FileExists = 6,
+    NoSuchUser = 7,
+    OptionNegotiation = 8,
 }
 
-impl<'data> Data<'data> {
-    fn new(ack: &Acknowledgement, session: &'data mut Session) -> Option<Self> {
-        let current_slice = (ack.block as usize)
-            .checked_mul(session.block_size)
-            .unwrap();
+impl ErrorCode {
+    /// The description RFC 1350 gives each code
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NotDefined => "Not defined",
+            Self::FileNotFound => "File not found",
+            Self::AccessViolation => "Access violation",
+            Self::DiskFull => "Disk full or allocation exceeded",
+            Self::IllegalOperation => "Illegal TFTP operation",
+            Self::UnknownTransferId => "Unknown transfer ID",
+            Self::FileExists => "File already exists",
+            Self::NoSuchUser => "No such user",
+            Self::OptionNegotiation => "Option negotiation failed",
+        }
+    }
+}
 
-        // We have recieved an ack for the final block so dont send
-        // more data
-        if current_slice > session.file_size {
-            return None;
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::FileNotFound,
+            2 => Self::AccessViolation,
+            3 => Self::DiskFull,
+            4 => Self::IllegalOperation,
+            5 => Self::UnknownTransferId,
+            6 => Self::FileExists,
+            7 => Self::NoSuchUser,
+            8 => Self::OptionNegotiation,
+            _ => Self::NotDefined,
         }
+    }
+}
 
-        let mut current_slice_end = current_slice.checked_add(session.block_size).unwrap();
-        if current_slice_end > session.file_size {
-            current_slice_end = session.file_size;
+impl From<&io::Error> for ErrorCode {
+    fn from(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => Self::FileNotFound,
+            io::ErrorKind::PermissionDenied => Self::AccessViolation,
+            io::ErrorKind::AlreadyExists => Self::FileExists,
+            io::ErrorKind::StorageFull => Self::DiskFull,
+            _ => Self::NotDefined,
         }
+    }
+}
 
-        Some(Self {
-            block: ack.block + 1,
-            data: &session.data[current_slice..current_slice_end],
-        })
+impl From<&Error> for ErrorCode {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Parse(ParseError::InvalidOptionValue { .. }) => Self::OptionNegotiation,
+            Error::Parse(_) => Self::IllegalOperation,
+            Error::Protocol(ProtocolError::UnknownTransferId) => Self::UnknownTransferId,
+            Error::Protocol(
+                ProtocolError::TimedOut { .. }
+                | ProtocolError::Idle(_)
+                | ProtocolError::TooManyTransfers,
+            ) => Self::NotDefined,
+            Error::Protocol(ProtocolError::ReadOnly) => Self::AccessViolation,
+            Error::Protocol(ProtocolError::OptionOutOfRange { .. }) => Self::OptionNegotiation,
+            Error::Protocol(ProtocolError::Remote { code, .. }) => *code,
+            Error::Protocol(_) => Self::IllegalOperation,
+            Error::Io(error) => error.into(),
+        }
     }
 }
 
+/// An ERROR packet, which ends the transfer for whoever receives it
 #[derive(Debug)]
-pub struct OptionAcknowledgement {
-    options: Vec<TftpOption>,
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
 }
 
-impl OptionAcknowledgement {
-    fn new(req: &ReadRequest, session: &mut Session) -> Self {
-        // Confirm options
-        let options: Vec<TftpOption> = req
-            .options
-            .iter()
-            .map(|option| match option {
-                TftpOption::TransferSize(_) => TftpOption::TransferSize(session.file_size),
-                TftpOption::BlockSize(block_size) => {
-                    session.block_size = *block_size;
-                    TftpOption::BlockSize(*block_size)
-                }
-                TftpOption::WindowSize(window_size) => {
-                    session.window_size = *window_size;
-                    TftpOption::WindowSize(*window_size)
-                }
-            })
-            .collect();
+impl ErrorMessage {
+    /// An ERROR packet with the standard message for its code
+    pub fn new(code: ErrorCode) -> Self {
+        Self {
+            code,
+            message: code.message().into(),
+        }
+    }
+
+    /// Let the client know why we could not carry on with the transfer. I/O errors get the
+    /// generic message for their code so we dont leak details of the server to the client
+    fn from_error(error: &Error) -> Self {
+        let code = ErrorCode::from(error);
+        let message = match error {
+            Error::Io(_) => code.message().into(),
+            error => error.to_string(),
+        };
 
-        Self { options }
+        Self { code, message }
     }
 }
 
+/// A DATA packet, `data` is shorter than the block size only for the final block
+#[derive(Debug)]
+pub struct Data<'data> {
+    pub block: u16,
+    pub data: &'data [u8],
+}
+
+/// An OACK packet confirming the options from a request that were accepted
+#[derive(Debug)]
+pub struct OptionAcknowledgement {
+    pub options: Vec<TftpOption>,
+}
+
+/// An ACK packet, block 0 acknowledges an OACK or accepts a write request
 #[derive(Debug)]
 pub struct Acknowledgement {
-    block: u16,
+    pub block: u16,
 }
 
+/// The body of both a read (RRQ) and a write (WRQ) request
 #[derive(Debug)]
-pub struct ReadRequest<'tftp> {
-    filename: &'tftp CStr,
-    mode: &'tftp CStr,
-    options: Vec<TftpOption>,
+pub struct Request<'tftp> {
+    pub filename: &'tftp str,
+    pub mode: &'tftp str,
+    pub options: Vec<TftpOption>,
 }
 
 impl Serialise for Tftp<'_> {
@@ -212,6 +418,21 @@ impl Serialise for Tftp<'_> {
         let mut bytes = Vec::new();
 
         match self {
+            Tftp::ReadRequest(req) | Tftp::WriteRequest(req) => {
+                let op_code = match self {
+                    Tftp::ReadRequest(_) => OpCode::ReadRequest,
+                    _ => OpCode::WriteRequest,
+                };
+                bytes.extend_from_slice(&op_code.serialise());
+                bytes.extend_from_slice(req.filename.as_bytes());
+                bytes.push(TftpOption::NULL);
+                bytes.extend_from_slice(req.mode.as_bytes());
+                bytes.push(TftpOption::NULL);
+                req.options
+                    .iter()
+                    .for_each(|option| bytes.extend_from_slice(&option.serialise()));
+                bytes
+            }
             Tftp::OptionAcknowledgement(res) => {
                 bytes.extend_from_slice(&OpCode::OptionAcknowledgement.serialise());
                 res.options
@@ -222,63 +443,274 @@ impl Serialise for Tftp<'_> {
             Tftp::Data(res) => {
                 bytes.extend_from_slice(&OpCode::Data.serialise());
                 bytes.extend_from_slice(&res.block.to_be_bytes());
-                bytes.extend_from_slice(&res.data);
+                bytes.extend_from_slice(res.data);
+                bytes
+            }
+            Tftp::Acknowledgement(res) => {
+                bytes.extend_from_slice(&OpCode::Acknowledgement.serialise());
+                bytes.extend_from_slice(&res.block.to_be_bytes());
+                bytes
+            }
+            Tftp::Error(res) => {
+                bytes.extend_from_slice(&OpCode::Error.serialise());
+                bytes.extend_from_slice(&(res.code as u16).to_be_bytes());
+                bytes.extend_from_slice(res.message.as_bytes());
+                bytes.push(TftpOption::NULL);
                 bytes
             }
-            _ => unimplemented!("{self:?}"),
         }
     }
 }
 
 impl<'tftp> Tftp<'tftp> {
     const OP_CODE_LEN: usize = 2;
+    const BLOCK_LEN: usize = 2;
     const NULL_SIZE: usize = 1;
 
-    fn parse(data: &'tftp [u8]) -> Self {
-        let op_code = data[1].try_into();
+    fn parse_request(data: &'tftp [u8]) -> Result<Request<'tftp>, ParseError> {
         let mut ptr = Self::OP_CODE_LEN;
 
-        match op_code {
-            Ok(OpCode::ReadRequest) => {
-                let filename = CStr::from_bytes_until_nul(&data[ptr..]).unwrap();
-                ptr += filename.to_bytes().len() + Self::NULL_SIZE;
+        let filename = slice_to_str(data, ptr)?;
+        ptr += filename.len() + Self::NULL_SIZE;
 
-                let mode = CStr::from_bytes_until_nul(&data[ptr..]).unwrap();
-                ptr += mode.to_bytes().len() + Self::NULL_SIZE;
+        let mode = slice_to_str(data, ptr)?;
+        ptr += mode.len() + Self::NULL_SIZE;
 
-                let options = TftpOption::parse(&data[ptr..]);
+        let options = TftpOption::parse(&data[ptr..])?;
 
-                Self::ReadRequest(ReadRequest {
-                    filename,
-                    mode,
-                    options,
-                })
-            }
-            Ok(OpCode::Acknowledgement) => Self::Acknowledgement(Acknowledgement {
-                block: u16::from_be_bytes([data[ptr], data[ptr + 1]]),
-            }),
-            _ => unimplemented!("{op_code:?}"),
-        }
+        Ok(Request {
+            filename,
+            mode,
+            options,
+        })
     }
 
-    fn respond(&self, session: &'tftp mut Session) -> Option<Self> {
+    /// The short name RFC 1350 gives each packet type
+    pub fn name(&self) -> &'static str {
         match self {
-            Self::ReadRequest(req) => {
-                let mut file = File::open(req.filename.to_str().unwrap()).unwrap();
-                session.file_size = file.read_to_end(&mut session.data).unwrap();
+            Self::ReadRequest(_) => "RRQ",
+            Self::WriteRequest(_) => "WRQ",
+            Self::Acknowledgement(_) => "ACK",
+            Self::OptionAcknowledgement(_) => "OACK",
+            Self::Data(_) => "DATA",
+            Self::Error(_) => "ERROR",
+        }
+    }
+
+    /// Decode a single datagram, anything we can't make sense of is a [ParseError]
+    pub fn parse(data: &'tftp [u8]) -> Result<Self, ParseError> {
+        let op_code = slice_to_u16(data, 0)?.try_into()?;
+        let ptr = Self::OP_CODE_LEN;
 
-                Some(Tftp::OptionAcknowledgement(OptionAcknowledgement::new(
-                    req, session,
-                )))
+        Ok(match op_code {
+            OpCode::ReadRequest => Self::ReadRequest(Self::parse_request(data)?),
+            OpCode::WriteRequest => Self::WriteRequest(Self::parse_request(data)?),
+            OpCode::Acknowledgement => Self::Acknowledgement(Acknowledgement {
+                block: slice_to_u16(data, ptr)?,
+            }),
+            OpCode::Data => Self::Data(Data {
+                block: slice_to_u16(data, ptr)?,
+                data: &data[ptr + Self::BLOCK_LEN..],
+            }),
+            OpCode::Error => Self::Error(ErrorMessage {
+                code: slice_to_u16(data, ptr)?.into(),
+                message: String::from_utf8_lossy(&data[ptr + Self::BLOCK_LEN..])
+                    .trim_end_matches('\0')
+                    .into(),
+            }),
+            OpCode::OptionAcknowledgement => Self::OptionAcknowledgement(OptionAcknowledgement {
+                options: TftpOption::parse(&data[ptr..])?,
+            }),
+        })
+    }
+
+    /// Build an ERROR packet telling a client why we are not carrying on
+    pub fn serialise_error(error: &Error) -> Vec<u8> {
+        Tftp::Error(ErrorMessage::from_error(error)).serialise()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(data: &[u8]) -> Result<Vec<TftpOption>, ParseError> {
+        TftpOption::parse(data)
+    }
+
+    /// Parse `packet`, serialise it again and check nothing changed on the way
+    fn round_trip(packet: Tftp) -> Vec<u8> {
+        let bytes = packet.serialise();
+        let parsed = Tftp::parse(&bytes).unwrap();
+        assert_eq!(parsed.name(), packet.name());
+        assert_eq!(parsed.serialise(), bytes);
+        bytes
+    }
+
+    #[test]
+    fn parses_option_names_in_any_case() {
+        assert_eq!(
+            options(b"BLKSIZE\x001428\0WindowSize\x008\0tSiZe\x000\0").unwrap(),
+            [
+                TftpOption::BlockSize(1428),
+                TftpOption::WindowSize(8),
+                TftpOption::TransferSize(0),
+            ]
+        );
+        assert_eq!(
+            options(b"Timeout\x003\0UTIMEOUT\x0050000\0ROLLover\x001\0Multicast\0\0").unwrap(),
+            [
+                TftpOption::Timeout(3),
+                TftpOption::TimeoutMicros(50000),
+                TftpOption::Rollover(1),
+                TftpOption::Multicast(String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_first_of_a_repeated_option() {
+        assert_eq!(
+            options(b"blksize\x001024\0BLKSIZE\x00512\0x-Foo\0one\0X-FOO\0two\0").unwrap(),
+            [
+                TftpOption::BlockSize(1024),
+                TftpOption::Unknown("x-Foo".into(), "one".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_unknown_options_through_as_sent() {
+        let parsed = options(b"X-Vendor\0Some Value\0").unwrap();
+        assert_eq!(
+            parsed,
+            [TftpOption::Unknown("X-Vendor".into(), "Some Value".into())]
+        );
+        assert_eq!(parsed[0].serialise(), b"X-Vendor\0Some Value\0");
+        assert_eq!(parsed[0].to_string(), "X-Vendor=Some Value");
+    }
+
+    #[test]
+    fn refuses_option_values_that_are_not_numbers() {
+        for (data, value) in [
+            (
+                &b"blksize\x0018446744073709551616\0"[..],
+                "18446744073709551616",
+            ),
+            (b"windowsize\0-1\0", "-1"),
+            (b"tsize\0\0", ""),
+            (b"timeout\0 5\0", " 5"),
+        ] {
+            match options(data) {
+                Err(ParseError::InvalidOptionValue { value: actual, .. }) => {
+                    assert_eq!(actual, value)
+                }
+                result => panic!("expected an invalid value, got {result:?}"),
             }
-            Self::Acknowledgement(req) => Data::new(req, session).map(Tftp::Data),
-            _ => unimplemented!("{self:?}"),
         }
+        // Big numbers are only a problem once negotiated, if they fit at all
+        assert_eq!(
+            options(b"blksize\x0018446744073709551615\0").unwrap(),
+            [TftpOption::BlockSize(usize::MAX)]
+        );
+    }
+
+    #[test]
+    fn refuses_a_truncated_option_list() {
+        assert!(options(b"").unwrap().is_empty());
+        for data in [
+            &b"blksize"[..],
+            b"blksize\0",
+            b"blksize\x001024",
+            b"x-foo\0bar",
+            b"blksize\x001024\0x-foo\0",
+        ] {
+            assert!(
+                matches!(options(data), Err(ParseError::UnterminatedString)),
+                "{data:?}"
+            );
+        }
+        assert!(matches!(
+            Tftp::parse(b"\0\x01file\0octet\0blksize\x001024"),
+            Err(ParseError::UnterminatedString)
+        ));
     }
 
-    pub fn handle(session: &mut Session, data: &'tftp [u8]) -> Option<Vec<u8>> {
-        Self::parse(data)
-            .respond(session)
-            .map(|response| response.serialise())
+    #[test]
+    fn round_trips_every_packet() {
+        let options = vec![
+            TftpOption::TransferSize(0),
+            TftpOption::BlockSize(1428),
+            TftpOption::WindowSize(16),
+            TftpOption::Timeout(2),
+            TftpOption::TimeoutMicros(250_000),
+            TftpOption::Rollover(0),
+            TftpOption::Multicast("239.255.0.1,1758,1".into()),
+            TftpOption::Unknown("x-foo".into(), "bar".into()),
+        ];
+        let request = || Request {
+            filename: "boot/pxelinux.0",
+            mode: "octet",
+            options: options.clone(),
+        };
+
+        let bytes = round_trip(Tftp::ReadRequest(request()));
+        assert_eq!(&bytes[..2], [0, 1]);
+        let Ok(Tftp::ReadRequest(parsed)) = Tftp::parse(&bytes) else {
+            panic!("expected a RRQ");
+        };
+        assert_eq!(
+            (parsed.filename, parsed.mode, parsed.options),
+            ("boot/pxelinux.0", "octet", options.clone())
+        );
+
+        let bytes = round_trip(Tftp::WriteRequest(request()));
+        assert_eq!(&bytes[..2], [0, 2]);
+
+        let bytes = round_trip(Tftp::Data(Data {
+            block: 65535,
+            data: b"hello",
+        }));
+        assert_eq!(bytes, b"\0\x03\xff\xffhello");
+        round_trip(Tftp::Data(Data {
+            block: 0,
+            data: b"",
+        }));
+
+        let bytes = round_trip(Tftp::Acknowledgement(Acknowledgement { block: 258 }));
+        assert_eq!(bytes, [0, 4, 1, 2]);
+
+        let bytes = round_trip(Tftp::Error(ErrorMessage::new(ErrorCode::DiskFull)));
+        assert_eq!(bytes, b"\0\x05\0\x03Disk full or allocation exceeded\0");
+
+        let bytes = round_trip(Tftp::OptionAcknowledgement(OptionAcknowledgement {
+            options: options.clone(),
+        }));
+        let Ok(Tftp::OptionAcknowledgement(parsed)) = Tftp::parse(&bytes) else {
+            panic!("expected an OACK");
+        };
+        assert_eq!(parsed.options, options);
+    }
+
+    #[test]
+    fn refuses_truncated_packets() {
+        assert!(matches!(
+            Tftp::parse(b"\0"),
+            Err(ParseError::Truncated { .. })
+        ));
+        assert!(matches!(
+            Tftp::parse(b"\0\x07"),
+            Err(ParseError::InvalidOpCode(7))
+        ));
+        for packet in [&b"\0\x03\0"[..], b"\0\x04", b"\0\x05\0"] {
+            assert!(matches!(
+                Tftp::parse(packet),
+                Err(ParseError::Truncated { .. })
+            ));
+        }
+        assert!(matches!(
+            Tftp::parse(b"\0\x01file"),
+            Err(ParseError::UnterminatedString)
+        ));
     }
 }